@@ -0,0 +1,208 @@
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::watch;
+
+use crate::error::{Context, Error, Result};
+use crate::sessions::Sessions;
+
+/// How often server logs requests rejected by ACL.
+const LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// IP network, `192.0.2.0/24` or `2001:db8::/32`. Plain address is a network
+/// of a single host.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => mask_v4(u32::from(ip), self.prefix) == u32::from(net),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => mask_v6(u128::from(ip), self.prefix) == u128::from(net),
+            _ => false,
+        }
+    }
+}
+
+fn mask_v4(addr: u32, prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix as u32).map_or(0, |mask| addr & mask)
+}
+
+fn mask_v6(addr: u128, prefix: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix as u32).map_or(0, |mask| addr & mask)
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (addr, prefix) = s.split_once('/').map_or((s, None), |(addr, prefix)| (addr, Some(prefix)));
+        let addr: IpAddr = addr.parse().map_err(|e| format!("{s}: {e}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("{s}: prefix length is not in 0..={max}"))?,
+            None => max,
+        };
+
+        // Host bits are ignored, like `10.1.2.3/8` means `10.0.0.0/8`
+        let addr = match addr {
+            IpAddr::V4(ip) => IpAddr::V4(mask_v4(u32::from(ip), prefix).into()),
+            IpAddr::V6(ip) => IpAddr::V6(mask_v6(u128::from(ip), prefix).into()),
+        };
+
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+/// Deny rules win over allow rules. If there are allow rules, sources which
+/// match none of them are denied too.
+pub(crate) struct Rules {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl Rules {
+    fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|net| net.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip))
+    }
+}
+
+/// Where rules come from: command line and an optional file where every
+/// line is `allow <cidr>` or `deny <cidr>`. Empty lines and lines starting
+/// with `#` are skipped.
+pub(crate) struct AclConfig {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+    pub file: Option<PathBuf>,
+}
+
+impl AclConfig {
+    pub(crate) fn rules(&self) -> Result<Rules> {
+        let mut rules = Rules {
+            allow: self.allow.clone(),
+            deny: self.deny.clone(),
+        };
+
+        let Some(path) = &self.file else {
+            return Ok(rules);
+        };
+
+        let content = fs::read_to_string(path).with_context(|| format!("acl: couldn't read {}", path.display()))?;
+        let config_error = |line, message| Error::Config {
+            path: path.clone(),
+            line,
+            message,
+        };
+
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (action, cidr) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| config_error(n + 1, "expected allow or deny and a network".to_string()))?;
+            let cidr: Cidr = cidr.trim().parse().map_err(|e| config_error(n + 1, e))?;
+
+            match action {
+                "allow" => rules.allow.push(cidr),
+                "deny" => rules.deny.push(cidr),
+                _ => return Err(config_error(n + 1, format!("unknown action {action}"))),
+            }
+        }
+
+        Ok(rules)
+    }
+}
+
+/// Server side view of the rules, which may be replaced while the server
+/// runs. Rejected requests are counted in server statistics.
+pub(crate) struct Acl {
+    rules: watch::Receiver<Rules>,
+    sessions: Arc<Sessions>,
+    unlogged: u64,
+    logged_at: Option<Instant>,
+}
+
+impl Acl {
+    pub(crate) fn new(rules: watch::Receiver<Rules>, sessions: Arc<Sessions>) -> Self {
+        Acl {
+            rules,
+            sessions,
+            unlogged: 0,
+            logged_at: None,
+        }
+    }
+
+    pub(crate) fn permits(&mut self, ip: IpAddr) -> bool {
+        if self.rules.borrow().permits(ip) {
+            return true;
+        }
+
+        let rejected = self.sessions.acl_rejected();
+        self.unlogged += 1;
+
+        // Logging every request would let anyone flood the log
+        if self.logged_at.is_none_or(|logged_at| logged_at.elapsed() >= LOG_INTERVAL) {
+            println!(
+                "Rejected {} requests by ACL ({rejected} so far), the last one from {ip}",
+                self.unlogged
+            );
+            self.unlogged = 0;
+            self.logged_at = Some(Instant::now());
+        }
+
+        false
+    }
+}
+
+/// Reloads rules on SIGHUP, the old rules stay if the new ones are broken.
+/// Never resolves if there is no file to reload.
+pub(crate) async fn reload_on_hangup(config: AclConfig, rules: watch::Sender<Rules>) -> Result<()> {
+    let Some(path) = &config.file else {
+        return std::future::pending().await;
+    };
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup()).context("acl: couldn't handle SIGHUP")?;
+
+        while hangup.recv().await.is_some() {
+            match config.rules() {
+                Ok(new) => {
+                    println!(
+                        "Reloaded ACL from {}: {} allow and {} deny rules",
+                        path.display(),
+                        new.allow.len(),
+                        new.deny.len()
+                    );
+                    rules.send_replace(new);
+                }
+                Err(e) => println!("Couldn't reload ACL, keeping the old one: {e}"),
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = (path, rules);
+
+    std::future::pending().await
+}
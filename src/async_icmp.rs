@@ -1,55 +1,92 @@
-use std::{net::{Ipv4Addr, SocketAddr}, time::Instant};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::{io, net::{IpAddr, Ipv6Addr, SocketAddr}, process};
 
 use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::watch;
 
-use crate::pinger::{Echo, MsgType, PingReqResp, PING_HDR_LEN};
+use crate::error::{self, is_transient, Context};
+use crate::pinger::{self, Echo, MsgType, PingReqResp, RequestParams, PING_HDR_LEN};
 
 const IP_HEADER_LEN: usize = 20;
 const ICMP_HEADER_LEN: usize = 8;
 
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+const IPPROTO_ICMPV6: u8 = 58;
+
 pub(crate) async fn pinger_transport(
     mut from_generator: Receiver<PingReqResp>,
     to_statista: Sender<PingReqResp>,
     _local_address: SocketAddr,
     mut remote_address: SocketAddr,
-    request_size: Option<u16>,
-    response_size: Option<u16>,
-) {
-    let sock = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4)).expect("should be able to create socket");
-    sock.set_nonblocking(true).expect("should be able to set nonblocking for socket");
-    let sock = tokio::net::UdpSocket::from_std(sock.into()).expect("should be able to create async socket from fd");
+    params: RequestParams,
+    mut drained: watch::Receiver<bool>,
+) -> error::Result<()> {
+    let (sock, kind) = open_socket(&remote_address).context("icmp: couldn't open socket")?;
+    sock.set_nonblocking(true).context("icmp: couldn't make socket nonblocking")?;
+    let sock = UdpSocket::from_std(sock.into()).context("icmp: couldn't register socket")?;
 
     remote_address.set_port(0);
-    sock.connect(remote_address).await.expect("pinger: should be able to connect socket");
+    sock.connect(remote_address)
+        .await
+        .with_context(|| format!("icmp: couldn't connect to {}", remote_address.ip()))?;
+
+    // ICMPv6 checksum covers the pseudo-header, so we need to know which
+    // source address the kernel picked for this destination. For datagram
+    // sockets the kernel also picks identifier, it is reported as local port.
+    let local_address = sock.local_addr().context("icmp: couldn't get local address")?;
+    let local_ip = local_address.ip();
+    let identifier = match kind {
+        SocketKind::Dgram => local_address.port(),
+        SocketKind::Raw => random_identifier(),
+    };
 
     let mut buf = [0; u16::MAX as usize];
+    let mut last_sent: u64 = 0;
+
+    // Once generator is done, replies are still received until statistics
+    // resolves every request, either by its reply or by its timeout.
+    let mut generating = true;
 
     loop {
         tokio::select! {
-            r_val = from_generator.recv() => {
+            r_val = from_generator.recv(), if generating => {
                 match r_val {
                     Some(mut req) => {
                         // Sending request to socket
                         let index = req.index;
                         req.timestamp = Instant::now();
+                        req.wall_time = SystemTime::now();
+                        last_sent = index;
 
-                        let r = Echo {
-                            id: index,
-                            len: request_size.unwrap_or(PING_HDR_LEN as u16),
-                            resp_size: response_size.unwrap_or(PING_HDR_LEN as u16),
+                        // Only the low 16 bits fit into ICMP header, full index
+                        // travels in the echo payload.
+                        let seq = index as u16;
+
+                        let r = params.request(index);
+
+                        let echo_request = match remote_address {
+                            SocketAddr::V4(_) => ICMP_ECHO_REQUEST,
+                            SocketAddr::V6(_) => ICMPV6_ECHO_REQUEST,
                         };
 
                         let icmp_header = vec![
-                            0x08, 0x00,   // Type, Code: Echo request
+                            echo_request, 0x00,   // Type, Code: Echo request
                             0x00, 0x00,   // Checksum placeholder
-                            0x12, 0x34,   // Identifier
-                            (index >> 8) as u8, (index & 0xff) as u8,  // Sequence number
+                            (identifier >> 8) as u8, (identifier & 0xff) as u8,   // Identifier
+                            (seq >> 8) as u8, (seq & 0xff) as u8,  // Sequence number
                         ];
 
-                        let mut send_buf = bincode::serialize(&r).unwrap();
+                        let mut send_buf = r.encode();
 
-                        if let Some(size) = request_size {
+                        if let Some(size) = params.request_size {
                             send_buf.resize(size as usize + ICMP_HEADER_LEN, 0);
                         } else {
                             send_buf.resize(PING_HDR_LEN + ICMP_HEADER_LEN, 0);
@@ -58,42 +95,126 @@ pub(crate) async fn pinger_transport(
                         send_buf.copy_within(0..len, ICMP_HEADER_LEN);
                         send_buf[..ICMP_HEADER_LEN].copy_from_slice(&icmp_header);
 
-                        let checksum = csum16_slice(&send_buf);
+                        let checksum = match (local_ip, remote_address.ip()) {
+                            (IpAddr::V6(src), IpAddr::V6(dst)) => icmpv6_checksum(&src, &dst, &send_buf),
+                            _ => csum16_slice(&send_buf),
+                        };
                         send_buf[2] = (checksum >> 8) as u8;
                         send_buf[3] = (checksum & 0xff) as u8;
 
                         // println!("TX ICMP: {:02X?}", &send_buf[..ICMP_HEADER_LEN]);
                         // println!("TX DATA: {:02X?}", &send_buf[ICMP_HEADER_LEN..]);
 
-                        sock.send(&send_buf).await.expect("tx: should send to socket normally");
-                        to_statista.send(req).await.expect("tx: should send request to stats normally");
+                        let sent = sock.send(&send_buf).await;
+
+                        // Request which couldn't be sent is still counted,
+                        // it is going to be lost.
+                        to_statista.send(req).await?;
+
+                        match sent {
+                            Ok(_) => {}
+                            Err(e) if is_transient(&e) => {
+                                let message = format!("icmp: couldn't send to {}: {e}", remote_address.ip());
+                                to_statista.send(PingReqResp::error(Some(index), message)).await?;
+                            }
+                            Err(e) => {
+                                return Err(e).with_context(|| format!("icmp: couldn't send to {}", remote_address.ip()));
+                            }
+                        }
+                    }
+                    None => {
+                        generating = false;
+                        to_statista.send(PingReqResp::finished()).await?;
                     }
-                    None => break,
                 }
             }
-            r_val = sock.recv(&mut buf) => {
-                if r_val.is_err() {
-                    break;
-                }
-
-                const DATA_OFFSET: usize = IP_HEADER_LEN + ICMP_HEADER_LEN;
+            _ = pinger::drained(&mut drained) => break,
+            r_val = sock.recv_from(&mut buf) => {
+                let (amt, from) = match r_val {
+                    Ok(r) => r,
+                    Err(e) if is_transient(&e) => {
+                        let message = format!("icmp: couldn't receive from {}: {e}", remote_address.ip());
+                        to_statista.send(PingReqResp::error(None, message)).await?;
+                        continue;
+                    }
+                    Err(e) => {
+                        return Err(e).with_context(|| format!("icmp: couldn't receive from {}", remote_address.ip()));
+                    }
+                };
 
-                // println!("RX IP:   {:02X?}", &buf[..IP_HEADER_LEN]);
-                // println!("RX ICMP: {:02X?}", &buf[IP_HEADER_LEN..IP_HEADER_LEN + ICMP_HEADER_LEN]);
-                // println!("RX DATA: {:02X?}", &buf[DATA_OFFSET..]);
+                let p_resp: Echo = match echo_reply_payload(&buf[..amt], &from, &local_ip, &remote_address, kind, identifier) {
+                    // Replies are generated by the kernel, so the payload is
+                    // our own request echoed back as is.
+                    Ok(Some((seq, data))) => {
+                        let index = unwrap_sequence(seq, last_sent);
+                        match Echo::decode(data) {
+                            Ok(echo) if echo.id == index => echo,
+                            Ok(echo) => {
+                                let message = format!(
+                                    "icmp: dropping reply from {}: payload index {} doesn't match sequence {seq}",
+                                    from.ip(),
+                                    echo.id,
+                                );
+                                to_statista.send(PingReqResp::error(Some(index), message)).await?;
+                                continue;
+                            }
+                            Err(e) => {
+                                let message = format!("icmp: dropping reply from {}: bad echo payload: {e}", from.ip());
+                                to_statista.send(PingReqResp::error(Some(index), message)).await?;
+                                continue;
+                            }
+                        }
+                    }
+                    Ok(None) => continue,
+                    Err(reason) => {
+                        let message = format!("icmp: dropping reply from {}: {reason}", from.ip());
+                        to_statista.send(PingReqResp::error(None, message)).await?;
+                        continue;
+                    }
+                };
 
-                if good_packet(&buf, &remote_address) {
-                    let p_resp: Echo = bincode::deserialize(&buf[DATA_OFFSET..DATA_OFFSET + PING_HDR_LEN]).unwrap();
-                    let req = PingReqResp {
-                        index: p_resp.id,
-                        timestamp: Instant::now(),
-                        t: MsgType::Response,
-                    };
-                    to_statista.send(req).await.unwrap();
-                }
+                let req = PingReqResp {
+                    index: p_resp.id,
+                    timestamp: Instant::now(),
+                    wall_time: SystemTime::now(),
+                    t: MsgType::Response,
+                    server_timestamps: None,
+                };
+                to_statista.send(req).await?;
             }
         }
     }
+
+    Ok(())
+}
+
+/// How the ICMP socket was opened. Datagram ("ping") sockets don't need any
+/// privileges, but the kernel owns the echo identifier and strips the IP
+/// header from received packets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SocketKind {
+    Dgram,
+    Raw,
+}
+
+/// Opens an unprivileged datagram ICMP socket if `net.ipv4.ping_group_range`
+/// allows it, and falls back to a raw socket otherwise.
+fn open_socket(remote_address: &SocketAddr) -> io::Result<(Socket, SocketKind)> {
+    let (domain, protocol) = match remote_address {
+        SocketAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+        SocketAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+    };
+
+    match Socket::new(domain, Type::DGRAM, Some(protocol)) {
+        Ok(sock) => Ok((sock, SocketKind::Dgram)),
+        Err(dgram_err) => match Socket::new(domain, Type::RAW, Some(protocol)) {
+            Ok(sock) => Ok((sock, SocketKind::Raw)),
+            Err(raw_err) => Err(io::Error::new(
+                raw_err.kind(),
+                format!("datagram socket: {dgram_err}, raw socket: {raw_err}"),
+            )),
+        },
+    }
 }
 
 fn csum16_add(x: u16, y: u16) -> u16 {
@@ -106,33 +227,132 @@ fn csum16_add(x: u16, y: u16) -> u16 {
     }
 }
 
-fn csum16_slice(data: &[u8]) -> u16 {
-    assert!(data.len() %2 == 0);
-
-    let mut csum = 0;
-    for chunk in data.chunks_exact(2) {
+/// Adds `data` to a running one's complement sum. An odd trailing byte is
+/// padded with zero, as RFC 1071 requires.
+fn csum16_add_slice(mut csum: u16, data: &[u8]) -> u16 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
         let hi = chunk[0] as u16;
         let lo = chunk[1] as u16;
         csum = csum16_add(csum, (hi << 8) | lo);
     }
+    if let [last] = chunks.remainder() {
+        csum = csum16_add(csum, (*last as u16) << 8);
+    }
+
+    csum
+}
+
+fn csum16_slice(data: &[u8]) -> u16 {
+    !csum16_add_slice(0, data)
+}
+
+/// ICMPv6 checksum over the IPv6 pseudo-header (RFC 8200, section 8.1)
+/// followed by the ICMPv6 message itself.
+fn icmpv6_checksum(src: &Ipv6Addr, dst: &Ipv6Addr, icmp: &[u8]) -> u16 {
+    let mut csum = csum16_add_slice(0, &src.octets());
+    csum = csum16_add_slice(csum, &dst.octets());
+    csum = csum16_add_slice(csum, &(icmp.len() as u32).to_be_bytes());
+    csum = csum16_add_slice(csum, &[0, 0, 0, IPPROTO_ICMPV6]);
+    csum = csum16_add_slice(csum, icmp);
 
     !csum
 }
 
-fn good_packet(buf: &[u8], remote_address: &SocketAddr) -> bool {
-    let ip = &buf[..IP_HEADER_LEN];
-    let ip_addr = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
-    if remote_address.ip() != ip_addr {
-        return false;
+/// Returns sequence number and echo payload of the packet if it is an echo
+/// reply to one of our requests, `Ok(None)` if the packet is somebody else's
+/// ICMP traffic, and an error describing the problem if the packet is
+/// malformed.
+///
+/// Raw IPv4 sockets deliver the IP header along with the ICMP message, raw
+/// ICMPv6 and datagram sockets deliver only the ICMP message.
+fn echo_reply_payload<'a>(
+    buf: &'a [u8],
+    from: &SocketAddr,
+    local_ip: &IpAddr,
+    remote_address: &SocketAddr,
+    kind: SocketKind,
+    identifier: u16,
+) -> Result<Option<(u16, &'a [u8])>, &'static str> {
+    if from.ip() != remote_address.ip() {
+        return Ok(None);
+    }
+
+    let icmp = match (remote_address, kind) {
+        (SocketAddr::V4(_), SocketKind::Raw) => strip_ipv4_header(buf)?,
+        _ => buf,
+    };
+
+    if icmp.len() < ICMP_HEADER_LEN {
+        return Err("truncated ICMP header");
+    }
+
+    let echo_reply = match remote_address {
+        SocketAddr::V4(_) => ICMP_ECHO_REPLY,
+        SocketAddr::V6(_) => ICMPV6_ECHO_REPLY,
+    };
+    if icmp[0] != echo_reply || icmp[1] != 0x00 {
+        return Ok(None);
+    }
+    if u16::from_be_bytes([icmp[4], icmp[5]]) != identifier {
+        return Ok(None);
+    }
+
+    let checksum = match (from.ip(), local_ip) {
+        (IpAddr::V6(src), IpAddr::V6(dst)) => icmpv6_checksum(&src, dst, icmp),
+        _ => csum16_slice(icmp),
+    };
+    if checksum != 0 {
+        return Err("bad ICMP checksum");
+    }
+
+    if icmp.len() < ICMP_HEADER_LEN + PING_HDR_LEN {
+        return Err("truncated echo payload");
+    }
+
+    let seq = u16::from_be_bytes([icmp[6], icmp[7]]);
+
+    Ok(Some((seq, &icmp[ICMP_HEADER_LEN..])))
+}
+
+/// Recovers full request index from 16-bit ICMP sequence number. Sequence
+/// wraps every 65536 requests, so the index is looked up in the current epoch
+/// of the last sent index, or in the previous one if that would point to a
+/// request which hasn't been sent yet.
+fn unwrap_sequence(seq: u16, last_sent: u64) -> u64 {
+    let epoch = last_sent >> 16;
+    let index = (epoch << 16) | seq as u64;
+
+    if index > last_sent && epoch > 0 {
+        index - (1 << 16)
+    } else {
+        index
     }
+}
 
-    let icmp = &buf[IP_HEADER_LEN..IP_HEADER_LEN + ICMP_HEADER_LEN];
-    if icmp[0] != 0x00 || icmp[1] != 0x00 {
-        return false;
+/// Skips IPv4 header of the packet using its IHL field, so that replies
+/// carrying IP options are parsed correctly.
+fn strip_ipv4_header(buf: &[u8]) -> Result<&[u8], &'static str> {
+    let first = *buf.first().ok_or("empty packet")?;
+    if first >> 4 != 4 {
+        return Err("not an IPv4 packet");
     }
-    if icmp[4] != 0x12 || icmp[5] != 0x34 {
-        return false;
+
+    let ihl = (first & 0x0f) as usize * 4;
+    if ihl < IP_HEADER_LEN || buf.len() < ihl {
+        return Err("bad IPv4 header length");
     }
 
-    true
+    Ok(&buf[ihl..])
+}
+
+/// Picks echo identifier for raw sockets. It has to differ between rup
+/// processes pinging the same host, otherwise they would count each other's
+/// replies.
+fn random_identifier() -> u16 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(process::id());
+    hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos());
+
+    hasher.finish() as u16
 }
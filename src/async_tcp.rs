@@ -1,83 +1,125 @@
 use std::net::SocketAddr;
-use std::time::Instant;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpSocket, TcpStream};
-use tokio::sync::mpsc;
-
-use crate::pinger::{Echo, MsgType, PingReqResp, PING_HDR_LEN};
-
-async fn server_connection_handler(mut sock: TcpStream) {
-    let peer_addr = sock.peer_addr().unwrap();
+use tokio::sync::{mpsc, watch};
+
+use crate::acl::Acl;
+use crate::auth::{Keys, Nonces, Rejection};
+use crate::framing::{write_echo, EchoReader};
+use crate::error::{Context, Error, Result};
+use crate::pinger::{self, EchoType, MsgType, PingReqResp, RequestParams};
+use crate::sessions::Sessions;
+
+async fn server_connection_handler(
+    sock: TcpStream,
+    peer_addr: SocketAddr,
+    keys: Option<Arc<Keys>>,
+    sessions: Arc<Sessions>,
+) {
     println!("New TCP connection from {peer_addr}");
 
-    let mut hdr_buf = [0; PING_HDR_LEN];
+    let (reader, writer) = sock.into_split();
+    serve_connection(reader, writer, peer_addr, keys, sessions).await;
+}
+
+/// Answers echo requests coming over a stream until the peer closes it. TLS
+/// transport uses it too.
+pub(crate) async fn serve_connection<R, W>(
+    reader: R,
+    mut writer: W,
+    peer_addr: SocketAddr,
+    keys: Option<Arc<Keys>>,
+    sessions: Arc<Sessions>,
+) where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut reader = EchoReader::new(reader);
 
     loop {
-        match sock.read(&mut hdr_buf).await {
-            Ok(0) => {
-                println!("Connection closed: {peer_addr}",);
+        match reader.read_echo().await {
+            Ok(None) => {
+                println!("Connection closed: {peer_addr}");
                 break;
             }
-            Ok(amt) => {
-                let mut req: Echo = bincode::deserialize(&hdr_buf).unwrap();
-
-                if req.len as usize > amt {
-                    let mut for_read_buf = vec![0; req.len as usize - amt];
+            Ok(Some(req)) => {
+                let received_at = SystemTime::now();
+
+                if req.msg_type != EchoType::Request {
+                    println!(
+                        "Unexpected message type {:?}, terminating connection with {peer_addr}",
+                        req.msg_type
+                    );
+                    break;
+                }
 
-                    match sock.read(&mut for_read_buf).await {
-                        Ok(0) => {
-                            println!("Connection closed: {peer_addr}");
-                            break;
+                let auth = match &keys {
+                    Some(keys) => match keys.verify(&req) {
+                        Ok(auth) => Some(auth),
+                        Err(rejection @ Rejection::Replayed) => {
+                            let n = sessions.rejected(rejection);
+                            println!("Dropping {rejection} packet from {peer_addr} ({n} so far)");
+                            continue;
                         }
-                        Ok(_) => {}
-                        Err(_) => {
-                            println!(
-                                "An error occurred during reading request, \
-                                    terminating connection with {peer_addr}"
-                            );
+                        Err(rejection) => {
+                            let n = sessions.rejected(rejection);
+                            println!("Dropping {rejection} packet ({n} so far), terminating connection with {peer_addr}");
                             break;
                         }
-                    }
+                    },
+                    None => None,
+                };
+
+                let mut resp = req.reply(received_at);
+                resp.stamp_transmit();
+                if let (Some(keys), Some(auth)) = (&keys, &auth) {
+                    keys.sign_reply(auth, &mut resp);
                 }
-                req.len = req.resp_size;
-                req.resp_size = 0;
-
-                let mut send_buf = bincode::serialize(&req).unwrap();
-                send_buf.resize(req.len as usize, 0);
-
-                match sock.write_all(&send_buf).await {
-                    Err(e) => {
-                        println!(
-                            "An error occured during writing echo, \
-                                    terminating connection with {peer_addr}: {e}"
-                        );
-                        break;
-                    }
-                    _ => continue,
+                sessions.record(peer_addr, req.id, req.len as usize, resp.len as usize);
+
+                if let Err(e) = write_echo(&mut writer, &resp).await {
+                    println!(
+                        "An error occured during writing echo, \
+                                terminating connection with {peer_addr}: {e}"
+                    );
+                    break;
                 }
             }
-            Err(_) => {
+            Err(e) => {
                 println!(
                     "An error occurred during reading request, \
-                          terminating connection with {peer_addr}"
+                          terminating connection with {peer_addr}: {e}"
                 );
                 break;
             }
         }
     }
+
+    sessions.close(peer_addr);
 }
 
-pub(crate) async fn server_transport(local_address: SocketAddr) {
+pub(crate) async fn server_transport(
+    local_address: SocketAddr,
+    keys: Option<Keys>,
+    mut acl: Acl,
+    sessions: Arc<Sessions>,
+) -> Result<()> {
     println!("Running TCP server listening {local_address}");
     let listen_sock = TcpListener::bind(local_address)
         .await
-        .expect("server: binding failed");
+        .with_context(|| format!("server: couldn't bind to {local_address}"))?;
+
+    let keys = keys.map(Arc::new);
 
     loop {
         match listen_sock.accept().await {
-            Ok((socket, _)) => {
-                tokio::spawn(server_connection_handler(socket));
+            // Connection is closed right away, nothing is read from it
+            Ok((_, peer_addr)) if !acl.permits(peer_addr.ip()) => {}
+            Ok((socket, peer_addr)) => {
+                tokio::spawn(server_connection_handler(socket, peer_addr, keys.clone(), sessions.clone()));
             }
             Err(e) => println!("Connection failed: {e}"),
         }
@@ -85,80 +127,121 @@ pub(crate) async fn server_transport(local_address: SocketAddr) {
 }
 
 pub(crate) async fn pinger_transport(
-    mut from_generator: mpsc::Receiver<PingReqResp>,
+    from_generator: mpsc::Receiver<PingReqResp>,
     to_statista: mpsc::Sender<PingReqResp>,
     local_address: SocketAddr,
     remote_address: SocketAddr,
-    request_size: Option<u16>,
-    response_size: Option<u16>,
-) {
-    let sock = TcpSocket::new_v4().unwrap();
-    sock.bind(local_address).expect("pinger: bind failed");
-    let mut sock = sock
-        .connect(remote_address)
+    params: RequestParams,
+    drained: watch::Receiver<bool>,
+) -> Result<()> {
+    let sock = connect(local_address, remote_address).await?;
+
+    let (reader, writer) = sock.into_split();
+    ping_stream(reader, writer, from_generator, to_statista, remote_address, params, drained).await
+}
+
+pub(crate) async fn connect(local_address: SocketAddr, remote_address: SocketAddr) -> Result<TcpStream> {
+    let sock = match remote_address {
+        SocketAddr::V4(_) => TcpSocket::new_v4(),
+        SocketAddr::V6(_) => TcpSocket::new_v6(),
+    }
+    .context("pinger: couldn't create socket")?;
+
+    // Default local address is an IPv4 wildcard, which IPv6 socket can't bind
+    if !local_address.ip().is_unspecified() || local_address.port() != 0 {
+        sock.bind(local_address)
+            .with_context(|| format!("pinger: couldn't bind to {local_address}"))?;
+    }
+    sock.connect(remote_address)
         .await
-        .expect("pinger: connection failed");
+        .with_context(|| format!("pinger: couldn't connect to {remote_address}"))
+}
 
-    let mut buf = [0; PING_HDR_LEN];
+/// Sends requests and receives replies over an established stream. TLS
+/// transport uses it too.
+pub(crate) async fn ping_stream<R, W>(
+    reader: R,
+    mut writer: W,
+    mut from_generator: mpsc::Receiver<PingReqResp>,
+    to_statista: mpsc::Sender<PingReqResp>,
+    remote_address: SocketAddr,
+    params: RequestParams,
+    mut drained: watch::Receiver<bool>,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut reader = EchoReader::new(reader);
+
+    let mut nonces = Nonces::new();
+
+    // Once generator is done, replies are still received until statistics
+    // resolves every request, either by its reply or by its timeout.
+    let mut generating = true;
 
     loop {
         tokio::select! {
-            r_val = from_generator.recv() => {
+            r_val = from_generator.recv(), if generating => {
                 match r_val {
                     Some(mut req) => {
                         // Sending request to socket
                         let index = req.index;
                         req.timestamp = Instant::now();
+                        req.wall_time = SystemTime::now();
 
-                        let r = Echo {
-                            id: index,
-                            len: request_size.unwrap_or(PING_HDR_LEN as u16),
-                            resp_size: response_size.unwrap_or(PING_HDR_LEN as u16),
-                        };
-
-                        let mut send_buf = bincode::serialize(&r).unwrap();
-
-                        if let Some(size) = request_size {
-                            send_buf.resize(size as usize, 0);
+                        let mut r = params.request(index);
+                        if let Some(keys) = &params.keys {
+                            keys.sign_request(&mut r, nonces.next());
                         }
 
-                        sock.write_all(&send_buf).await.expect("tx: couldn't send message");
+                        write_echo(&mut writer, &r)
+                            .await
+                            .with_context(|| format!("pinger: couldn't send to {remote_address}"))?;
 
-                        to_statista.send(req).await.expect("tx: couldn't send transformed request to client");
+                        to_statista.send(req).await?;
+                    }
+                    None => {
+                        generating = false;
+                        to_statista.send(PingReqResp::finished()).await?;
                     }
-                    None => break,
                 }
             }
-            r_val = sock.read(&mut buf) => {
-                if r_val.is_err() {
-                    break;
+            _ = pinger::drained(&mut drained) => break,
+            r_val = reader.read_echo() => {
+                let p_resp = r_val
+                    .with_context(|| format!("pinger: couldn't receive from {remote_address}"))?
+                    .ok_or(Error::ConnectionClosed(remote_address))?;
+
+                // Anyone on path may forge a reply, only authenticated ones may fail the run
+                if let Some(keys) = &params.keys {
+                    if let Err(rejection) = keys.verify(&p_resp) {
+                        to_statista.send(PingReqResp::rejected(rejection)).await?;
+                        continue;
+                    }
                 }
 
-                let p_resp: Echo = bincode::deserialize(&buf).unwrap();
+                p_resp.check_reply().map_err(|source| Error::Protocol {
+                    peer: remote_address,
+                    source,
+                })?;
 
                 let req = PingReqResp {
                     index: p_resp.id,
                     timestamp: Instant::now(),
-                    t: MsgType::Response
+                    wall_time: SystemTime::now(),
+                    t: MsgType::Response,
+                    server_timestamps: p_resp.timestamps,
                 };
 
-                if p_resp.len as usize > PING_HDR_LEN {
-                    let mut for_read_buf = vec![0; p_resp.len as usize - PING_HDR_LEN];
-
-                    match sock.read(&mut for_read_buf).await {
-                        Ok(0) => {
-                            panic!("Connection closed: {remote_address}");
-                        }
-                        Ok(_) => { }
-                        Err(_) => {
-                            panic!("An error occurred during reading response,\
-                                    terminating connection with {remote_address}");
-                        }
-                    }
-                }
-
-                to_statista.send(req).await.unwrap();
+                to_statista.send(req).await?;
             }
         }
     }
+
+    // Lets TLS server know the session is over, not cut
+    writer
+        .shutdown()
+        .await
+        .with_context(|| format!("pinger: couldn't close connection to {remote_address}"))
 }
@@ -0,0 +1,110 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime};
+
+use tokio::net::TcpSocket;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::time::timeout;
+
+use crate::error::Result;
+use crate::pinger::{MsgType, PingReqResp};
+
+/// Measures TCP handshake time: every request opens a new connection to the
+/// remote side and the reply is either SYN/ACK or RST. No rup server is needed,
+/// so any listening (or actively refusing) port can be pinged.
+pub(crate) async fn pinger_transport(
+    mut from_generator: Receiver<PingReqResp>,
+    to_statista: Sender<PingReqResp>,
+    local_address: SocketAddr,
+    remote_address: SocketAddr,
+    wait_time: Duration,
+) -> Result<()> {
+    while let Some(mut req) = from_generator.recv().await {
+        req.timestamp = Instant::now();
+        req.wall_time = SystemTime::now();
+        let index = req.index;
+        let sent_at = (req.timestamp, req.wall_time);
+
+        to_statista.send(req).await?;
+
+        // Running out of sockets or ports may be temporary, request which
+        // couldn't be sent is counted as lost.
+        match new_socket(&local_address, &remote_address) {
+            Ok(sock) => {
+                tokio::spawn(handshake(index, sent_at, sock, remote_address, wait_time, to_statista.clone()));
+            }
+            Err(e) => {
+                let message = format!("couldn't create socket: {e}");
+                to_statista.send(PingReqResp::error(Some(index), message)).await?;
+            }
+        }
+    }
+
+    // Handshakes in flight keep their own senders, statistics is done once
+    // they all complete or time out.
+    to_statista.send(PingReqResp::finished()).await?;
+
+    Ok(())
+}
+
+async fn handshake(
+    index: u64,
+    sent_at: (Instant, SystemTime),
+    sock: TcpSocket,
+    remote_address: SocketAddr,
+    wait_time: Duration,
+    to_statista: Sender<PingReqResp>,
+) {
+    // Time only the handshake itself, not the scheduling delay between the
+    // request being registered and this task being polled.
+    let started = Instant::now();
+
+    match timeout(wait_time, sock.connect(remote_address)).await {
+        Ok(Ok(stream)) => {
+            let rtt = started.elapsed();
+            // Reset the connection instead of closing it gracefully, so that
+            // neither side keeps sockets in TIME_WAIT for every ping.
+            let _ = stream.set_linger(Some(Duration::ZERO));
+            drop(stream);
+            reply(index, sent_at, rtt, &to_statista).await;
+        }
+        Ok(Err(e)) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            let rtt = started.elapsed();
+            reply(index, sent_at, rtt, &to_statista).await;
+        }
+        Ok(Err(e)) => {
+            let message = format!("connect to {remote_address} failed: {e}");
+            let _ = to_statista.send(PingReqResp::error(Some(index), message)).await;
+        }
+        // Timeout is reported by statistics as a lost request
+        Err(_) => {}
+    }
+}
+
+async fn reply(index: u64, sent_at: (Instant, SystemTime), rtt: Duration, to_statista: &Sender<PingReqResp>) {
+    let resp = PingReqResp {
+        index,
+        timestamp: sent_at.0 + rtt,
+        wall_time: sent_at.1 + rtt,
+        t: MsgType::Response,
+        server_timestamps: None,
+    };
+
+    // Statistics may be already gone if the run was interrupted
+    let _ = to_statista.send(resp).await;
+}
+
+fn new_socket(local_address: &SocketAddr, remote_address: &SocketAddr) -> io::Result<TcpSocket> {
+    let sock = match remote_address {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+
+    // Default local address is an IPv4 wildcard, binding to it would fail for
+    // IPv6 destinations and is not needed anyway.
+    if !local_address.ip().is_unspecified() || local_address.port() != 0 {
+        sock.bind(*local_address)?;
+    }
+
+    Ok(sock)
+}
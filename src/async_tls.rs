@@ -0,0 +1,248 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch};
+use tokio::time;
+use tokio_rustls::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::crypto::{self, CryptoProvider, WebPkiSupportedAlgorithms};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{self, ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::acl::Acl;
+use crate::async_tcp;
+use crate::auth::{self, Keys};
+use crate::error::{Context, Error, Result};
+use crate::pinger::{PingReqResp, RequestParams};
+use crate::sessions::Sessions;
+
+/// SHA-256 of DER-encoded server certificate, which client pins.
+pub(crate) type Fingerprint = [u8; 32];
+
+/// How long a client may take to complete TLS handshake. It is a couple of
+/// round trips, and a connection has no session to expire until it is done.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) async fn server_transport(
+    local_address: SocketAddr,
+    keys: Option<Keys>,
+    config: Arc<ServerConfig>,
+    mut acl: Acl,
+    sessions: Arc<Sessions>,
+) -> Result<()> {
+    println!("Running TLS server listening {local_address}");
+    let listen_sock = TcpListener::bind(local_address)
+        .await
+        .with_context(|| format!("server: couldn't bind to {local_address}"))?;
+
+    let acceptor = TlsAcceptor::from(config);
+    let keys = keys.map(Arc::new);
+
+    loop {
+        match listen_sock.accept().await {
+            // Connection is closed before TLS handshake
+            Ok((_, peer_addr)) if !acl.permits(peer_addr.ip()) => {}
+            Ok((socket, peer_addr)) => {
+                let acceptor = acceptor.clone();
+                let keys = keys.clone();
+                let sessions = sessions.clone();
+
+                tokio::spawn(async move {
+                    match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+                        Ok(Ok(stream)) => {
+                            println!("New TLS connection from {peer_addr}");
+                            let (reader, writer) = tokio::io::split(stream);
+                            async_tcp::serve_connection(reader, writer, peer_addr, keys, sessions).await;
+                        }
+                        Ok(Err(e)) => println!("TLS handshake with {peer_addr} failed: {e}"),
+                        Err(_) => println!("TLS handshake with {peer_addr} timed out"),
+                    }
+                });
+            }
+            Err(e) => println!("Connection failed: {e}"),
+        }
+    }
+}
+
+/// Server identity: certificate chain and key from PEM files, or a fresh
+/// self-signed certificate. If the files don't exist yet, the generated
+/// certificate is saved to them, so it stays the same across restarts.
+/// Fingerprint of the certificate is printed, clients pin it with
+/// `--tls-pin`.
+pub(crate) fn server_config(cert: Option<&Path>, key: Option<&Path>) -> Result<Arc<ServerConfig>> {
+    let (chain, key) = match (cert, key) {
+        (Some(cert), Some(key)) if !cert.exists() && !key.exists() => {
+            let generated = generate_certificate(Some((cert, key)))?;
+            println!("Saved generated TLS certificate to {} and its key to {}", cert.display(), key.display());
+            generated
+        }
+        (Some(cert), Some(key)) => {
+            let chain = CertificateDer::pem_file_iter(cert)
+                .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+                .map_err(|e| Error::Tls(format!("couldn't load certificate {}: {e}", cert.display())))?;
+            let key = PrivateKeyDer::from_pem_file(key)
+                .map_err(|e| Error::Tls(format!("couldn't load key {}: {e}", key.display())))?;
+            (chain, key)
+        }
+        _ => {
+            println!("TLS certificate is generated for this run only, use --tls-cert and --tls-key to keep it");
+            generate_certificate(None)?
+        }
+    };
+
+    let leaf = chain
+        .first()
+        .ok_or_else(|| Error::Tls("no certificate in certificate file".to_string()))?;
+    println!("TLS certificate fingerprint: {}", format_fingerprint(&Sha256::digest(leaf).into()));
+
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(chain, key))
+        .map_err(|e| Error::Tls(e.to_string()))?;
+
+    Ok(Arc::new(config))
+}
+
+/// Generates self-signed certificate and saves it to `files`, certificate
+/// and key, if there are any. The key is readable by the owner only.
+fn generate_certificate(
+    files: Option<(&Path, &Path)>,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let certified = rcgen::generate_simple_self_signed(vec!["rup".to_string()])
+        .map_err(|e| Error::Tls(format!("couldn't generate certificate: {e}")))?;
+
+    if let Some((cert, key)) = files {
+        fs::write(cert, certified.cert.pem())
+            .with_context(|| format!("tls: couldn't write certificate {}", cert.display()))?;
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(key)
+            .and_then(|mut file| file.write_all(certified.key_pair.serialize_pem().as_bytes()))
+            .with_context(|| format!("tls: couldn't write key {}", key.display()))?;
+    }
+
+    let key = PrivateKeyDer::Pkcs8(certified.key_pair.serialize_der().into());
+    Ok((vec![certified.cert.der().clone()], key))
+}
+
+/// Same as TCP transport, but the connection is wrapped in TLS. Duration of
+/// TLS handshake is reported separately, it isn't a part of any RTT.
+pub(crate) async fn pinger_transport(
+    from_generator: mpsc::Receiver<PingReqResp>,
+    to_statista: mpsc::Sender<PingReqResp>,
+    local_address: SocketAddr,
+    remote_address: SocketAddr,
+    params: RequestParams,
+    drained: watch::Receiver<bool>,
+    pin: Fingerprint,
+) -> Result<()> {
+    let provider = provider();
+    let verifier = PinnedCertVerifier {
+        pin,
+        algorithms: provider.signature_verification_algorithms,
+    };
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| Error::Tls(e.to_string()))?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    let connector = TlsConnector::from(Arc::new(config));
+
+    let sock = async_tcp::connect(local_address, remote_address).await?;
+
+    let started = Instant::now();
+    let stream = connector
+        .connect(ServerName::IpAddress(remote_address.ip().into()), sock)
+        .await
+        .with_context(|| format!("pinger: TLS handshake with {remote_address} failed"))?;
+    to_statista.send(PingReqResp::handshake(started.elapsed())).await?;
+
+    let (reader, writer) = tokio::io::split(stream);
+    async_tcp::ping_stream(reader, writer, from_generator, to_statista, remote_address, params, drained).await
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+/// Accepts the only certificate whose fingerprint is pinned, whoever issued
+/// it. Handshake signatures are still verified, so the server must own the
+/// key of that certificate.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    pin: Fingerprint,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let fingerprint: Fingerprint = Sha256::digest(end_entity).into();
+
+        if fingerprint == self.pin {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "server certificate {} is not the pinned one",
+                format_fingerprint(&fingerprint)
+            )))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Formats fingerprint as `sha256:` and colon separated hex bytes, like
+/// openssl does.
+fn format_fingerprint(fingerprint: &Fingerprint) -> String {
+    let hex: Vec<String> = fingerprint.iter().map(|b| format!("{b:02X}")).collect();
+    format!("sha256:{}", hex.join(":"))
+}
+
+/// Parses fingerprint printed by the server, `sha256:` prefix and colons
+/// are optional.
+pub(crate) fn parse_fingerprint(s: &str) -> std::result::Result<Fingerprint, String> {
+    let hex = s.strip_prefix("sha256:").unwrap_or(s).replace(':', "");
+
+    auth::parse_hex(&hex)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| format!("{s} is not a SHA-256 fingerprint"))
+}
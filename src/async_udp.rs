@@ -1,40 +1,106 @@
-use std::collections::HashSet;
 use std::net::SocketAddr;
-use std::time::Instant;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use tokio::net::UdpSocket;
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::watch;
+use tokio::time::{self, MissedTickBehavior};
 
-use crate::pinger::{Echo, MsgType, PingReqResp, PING_HDR_LEN};
+use crate::acl::Acl;
+use crate::auth::{Keys, Nonces};
+use crate::error::{is_transient, Context, Error, Result};
+use crate::pinger::{self, Echo, EchoType, MsgType, PingReqResp, RequestParams};
+use crate::policy::Policy;
+use crate::sessions::Sessions;
 
-pub(crate) async fn server_transport(local_address: SocketAddr) {
+/// How often sessions are checked for being idle.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+pub(crate) async fn server_transport(
+    local_address: SocketAddr,
+    mut policy: Policy,
+    keys: Option<Keys>,
+    mut acl: Acl,
+    sessions: Arc<Sessions>,
+    idle_timeout: Duration,
+) -> Result<()> {
     println!("Running UDP server listening {local_address}");
 
     let sock = UdpSocket::bind(local_address)
         .await
-        .expect("server: binding failed");
+        .with_context(|| format!("server: couldn't bind to {local_address}"))?;
 
-    let mut client_addrs: HashSet<SocketAddr> = HashSet::new();
+    let mut expiry = time::interval(EXPIRY_INTERVAL);
+    expiry.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
     let mut buf = [0; u16::MAX as usize];
 
     loop {
-        let (_, addr) = sock.recv_from(&mut buf).await.unwrap();
+        let (amt, addr) = tokio::select! {
+            r_val = sock.recv_from(&mut buf) => match r_val {
+                Ok(r) => r,
+                Err(e) => {
+                    println!("Couldn't receive request: {e}");
+                    continue;
+                }
+            },
+            _ = expiry.tick() => {
+                sessions.expire(idle_timeout);
+                continue;
+            }
+        };
+        let received_at = SystemTime::now();
 
-        if !client_addrs.contains(&addr) {
-            println!("New UDP request from {addr}");
-            client_addrs.insert(addr);
+        if !acl.permits(addr.ip()) {
+            continue;
+        }
+        if !policy.admit(addr.ip()) {
+            sessions.rate_limited();
+            continue;
         }
 
-        let mut req: Echo = bincode::deserialize(&buf[..PING_HDR_LEN]).unwrap();
-        req.len = req.resp_size;
-        req.resp_size = 0;
+        let req = match Echo::decode(&buf[..amt]) {
+            Ok(req) if req.msg_type == EchoType::Request => req,
+            Ok(req) => {
+                println!("Dropping packet from {addr}: unexpected message type {:?}", req.msg_type);
+                continue;
+            }
+            Err(e) => {
+                println!("Dropping packet from {addr}: {e}");
+                continue;
+            }
+        };
+
+        let auth = match &keys {
+            Some(keys) => match keys.verify(&req) {
+                Ok(auth) => Some(auth),
+                Err(rejection) => {
+                    let n = sessions.rejected(rejection);
+                    println!("Dropping {rejection} packet from {addr} ({n} so far)");
+                    continue;
+                }
+            },
+            None => None,
+        };
+
+        let mut resp = req.reply(received_at);
+        policy.shape(&req, amt, addr.ip(), &mut resp);
+        resp.stamp_transmit();
+        if let (Some(keys), Some(auth)) = (&keys, &auth) {
+            keys.sign_reply(auth, &mut resp);
+        }
+        let mut send_buf = resp.encode();
 
-        let mut send_buf = bincode::serialize(&req).unwrap();
+        send_buf.resize(resp.len as usize, 0);
 
-        send_buf.resize(req.len as usize, 0);
+        if sessions.record(addr, req.id, amt, send_buf.len()) {
+            println!("New UDP request from {addr}");
+        }
 
-        sock.send_to(&send_buf, addr).await.unwrap();
+        if let Err(e) = sock.send_to(&send_buf, addr).await {
+            println!("Couldn't send reply to {addr}: {e}");
+        }
     }
 }
 
@@ -43,59 +109,118 @@ pub(crate) async fn pinger_transport(
     to_statista: Sender<PingReqResp>,
     local_address: SocketAddr,
     remote_address: SocketAddr,
-    request_size: Option<u16>,
-    response_size: Option<u16>,
-) {
+    params: RequestParams,
+    mut drained: watch::Receiver<bool>,
+) -> Result<()> {
     let sock = UdpSocket::bind(local_address)
         .await
-        .expect("pinger: binding failed");
+        .with_context(|| format!("pinger: couldn't bind to {local_address}"))?;
     sock.connect(remote_address)
         .await
-        .expect("pinger: connect function failed");
+        .with_context(|| format!("pinger: couldn't connect to {remote_address}"))?;
 
     let mut buf = [0; u16::MAX as usize];
 
+    // The latest cookie of the server, if it requires them
+    let mut cookie = None;
+
+    let mut nonces = Nonces::new();
+
+    // Once generator is done, replies are still received until statistics
+    // resolves every request, either by its reply or by its timeout.
+    let mut generating = true;
+
     loop {
         tokio::select! {
-            r_val = from_generator.recv() => {
+            r_val = from_generator.recv(), if generating => {
                 match r_val {
                     Some(mut req) => {
                         // Sending request to socket
                         let index = req.index;
                         req.timestamp = Instant::now();
+                        req.wall_time = SystemTime::now();
 
-                        let r = Echo {
-                            id: index,
-                            len: request_size.unwrap_or(PING_HDR_LEN as u16),
-                            resp_size: response_size.unwrap_or(PING_HDR_LEN as u16),
-                        };
+                        let mut r = params.request(index);
+                        if let Some(cookie) = cookie {
+                            r.set_cookie(cookie);
+                        }
+                        if let Some(keys) = &params.keys {
+                            keys.sign_request(&mut r, nonces.next());
+                        }
 
-                        let mut send_buf = bincode::serialize(&r).unwrap();
+                        let mut send_buf = r.encode();
+                        send_buf.resize(r.len as usize, 0);
 
-                        if let Some(size) = request_size {
-                            send_buf.resize(size as usize, 0);
-                        }
+                        let sent = sock.send(&send_buf).await;
 
-                        sock.send(&send_buf).await.expect("tx: couldn't send message");
+                        // Request which couldn't be sent is still counted,
+                        // it is going to be lost.
+                        to_statista.send(req).await?;
 
-                        to_statista.send(req).await.expect("tx: couldn't send transformed request to client");
+                        match sent {
+                            Ok(_) => {}
+                            Err(e) if is_transient(&e) => {
+                                let message = format!("couldn't send to {remote_address}: {e}");
+                                to_statista.send(PingReqResp::error(Some(index), message)).await?;
+                            }
+                            Err(e) => return Err(e).with_context(|| format!("pinger: couldn't send to {remote_address}")),
+                        }
+                    }
+                    None => {
+                        generating = false;
+                        to_statista.send(PingReqResp::finished()).await?;
                     }
-                    None => break,
                 }
             }
+            _ = pinger::drained(&mut drained) => break,
             r_val = sock.recv(&mut buf) => {
-                if r_val.is_err() {
-                    break;
+                let amt = match r_val {
+                    Ok(amt) => amt,
+                    Err(e) if is_transient(&e) => {
+                        let message = format!("couldn't receive from {remote_address}: {e}");
+                        to_statista.send(PingReqResp::error(None, message)).await?;
+                        continue;
+                    }
+                    Err(e) => return Err(e).with_context(|| format!("pinger: couldn't receive from {remote_address}")),
+                };
+
+                let p_resp = match Echo::decode(&buf[..amt]) {
+                    Ok(p_resp) => p_resp,
+                    Err(e) => {
+                        let message = format!("Dropping packet from {remote_address}: {e}");
+                        to_statista.send(PingReqResp::error(None, message)).await?;
+                        continue;
+                    }
+                };
+
+                // Anyone on path may forge a reply, only authenticated ones may fail the run
+                if let Some(keys) = &params.keys {
+                    if let Err(rejection) = keys.verify(&p_resp) {
+                        to_statista.send(PingReqResp::rejected(rejection)).await?;
+                        continue;
+                    }
+                }
+
+                p_resp.check_reply().map_err(|source| Error::Protocol {
+                    peer: remote_address,
+                    source,
+                })?;
+
+                if p_resp.cookie.is_some() {
+                    cookie = p_resp.cookie;
                 }
 
-                let p_resp: Echo = bincode::deserialize(&buf[..PING_HDR_LEN]).unwrap();
                 let req = PingReqResp {
                     index: p_resp.id,
                     timestamp: Instant::now(),
+                    wall_time: SystemTime::now(),
                     t: MsgType::Response,
+                    server_timestamps: p_resp.timestamps,
                 };
-                to_statista.send(req).await.unwrap();
+                to_statista.send(req).await?;
             }
         }
     }
+
+    Ok(())
 }
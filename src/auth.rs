@@ -0,0 +1,259 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::error::{Context, Error, Result};
+use crate::pinger::{Auth, Echo, EchoType, FLAG_AUTH, MAC_LEN};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Shortest secret accepted, shorter ones are easy to brute force.
+const MIN_SECRET_LEN: usize = 16;
+
+/// Most a nonce may be off our wall clock. Older packets are taken for
+/// replays, as their nonces may be forgotten already, e.g. by a restart.
+const MAX_NONCE_SKEW: Duration = Duration::from_secs(60);
+
+/// Most nonces remembered per key, in case of more than that within
+/// `MAX_NONCE_SKEW`. Packets older than all of them are taken for replays.
+const MAX_NONCES: usize = 65536;
+
+/// Why a packet of an authenticated session was dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Rejection {
+    /// Packet isn't signed, or is signed with unknown key, or its HMAC
+    /// doesn't match.
+    Unauthenticated,
+    /// Nonce of the packet was already seen, or is too far from our clock.
+    Replayed,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::Unauthenticated => write!(f, "unauthenticated"),
+            Rejection::Replayed => write!(f, "replayed"),
+        }
+    }
+}
+
+/// Packets dropped by authentication so far.
+#[derive(Default)]
+pub(crate) struct Rejections {
+    pub unauthenticated: u64,
+    pub replayed: u64,
+}
+
+impl Rejections {
+    /// Counts `rejection` and returns how many of its kind there were.
+    pub(crate) fn count(&mut self, rejection: Rejection) -> u64 {
+        let counter = match rejection {
+            Rejection::Unauthenticated => &mut self.unauthenticated,
+            Rejection::Replayed => &mut self.replayed,
+        };
+        *counter += 1;
+        *counter
+    }
+}
+
+/// Shared secrets, loaded from a file where every line is a numeric key id
+/// and a hex-encoded secret separated by whitespace. Empty lines and lines
+/// starting with `#` are skipped. Client signs with the first key, server
+/// accepts any of them, so keys can be rotated without downtime.
+pub(crate) struct Keys {
+    keys: Vec<Key>,
+}
+
+struct Key {
+    id: u32,
+    secret: Vec<u8>,
+    /// Nonces of packets signed with the key, whoever sent them, so that a
+    /// packet can't be replayed from another address or connection.
+    nonces: Mutex<ReplayWindow>,
+}
+
+impl Keys {
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path).with_context(|| format!("keys: couldn't read {}", path.display()))?;
+        let config_error = |line, message: String| Error::Config {
+            path: path.to_path_buf(),
+            line,
+            message,
+        };
+
+        let mut keys: Vec<Key> = Vec::new();
+
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (id, secret) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| config_error(n + 1, "expected key id and secret".to_string()))?;
+            let id: u32 = id
+                .parse()
+                .map_err(|e| config_error(n + 1, format!("bad key id {id}: {e}")))?;
+            let secret = parse_hex(secret.trim()).ok_or_else(|| config_error(n + 1, "secret is not hex".to_string()))?;
+
+            if secret.len() < MIN_SECRET_LEN {
+                return Err(config_error(n + 1, format!("secret is shorter than {MIN_SECRET_LEN} bytes")));
+            }
+            if keys.iter().any(|key| key.id == id) {
+                return Err(config_error(n + 1, format!("duplicate key id {id}")));
+            }
+
+            keys.push(Key {
+                id,
+                secret,
+                nonces: Mutex::new(ReplayWindow::default()),
+            });
+        }
+
+        if keys.is_empty() {
+            return Err(config_error(0, "no keys".to_string()));
+        }
+
+        Ok(Keys { keys })
+    }
+
+    /// Signs request with the first key, `nonce` must never repeat.
+    pub(crate) fn sign_request(&self, echo: &mut Echo, nonce: u64) {
+        let key = &self.keys[0];
+        sign(echo, key.id, &key.secret, nonce);
+    }
+
+    /// Signs reply with the key of verified `request`. Version mismatch
+    /// replies have no room for authentication block and are sent as is,
+    /// clients with keys drop them like any other unauthenticated packet.
+    pub(crate) fn sign_reply(&self, request: &Auth, echo: &mut Echo) {
+        if echo.msg_type != EchoType::Reply {
+            return;
+        }
+        if let Some(key) = self.key(request.key_id) {
+            sign(echo, key.id, &key.secret, request.nonce);
+        }
+    }
+
+    /// Checks HMAC and nonce of the packet and returns its authentication
+    /// block.
+    pub(crate) fn verify(&self, echo: &Echo) -> std::result::Result<Auth, Rejection> {
+        let auth = echo.auth.ok_or(Rejection::Unauthenticated)?;
+        let key = self.key(auth.key_id).ok_or(Rejection::Unauthenticated)?;
+
+        let mut mac = HmacSha256::new_from_slice(&key.secret).expect("HMAC takes key of any length");
+        mac.update(&signed_part(echo));
+        mac.verify_slice(&auth.mac).map_err(|_| Rejection::Unauthenticated)?;
+
+        // Only signed nonces are remembered, forged packets can't fill the window
+        key.nonces.lock().unwrap().check(auth.nonce, now_nanos())?;
+
+        Ok(auth)
+    }
+
+    fn key(&self, key_id: u32) -> Option<&Key> {
+        self.keys.iter().find(|key| key.id == key_id)
+    }
+}
+
+fn sign(echo: &mut Echo, key_id: u32, secret: &[u8], nonce: u64) {
+    echo.flags |= FLAG_AUTH;
+    echo.auth = Some(Auth {
+        key_id,
+        nonce,
+        mac: [0; MAC_LEN],
+    });
+    echo.len = echo.len.max(echo.header_len() as u16);
+
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes key of any length");
+    mac.update(&signed_part(echo));
+
+    if let Some(auth) = &mut echo.auth {
+        auth.mac = mac.finalize().into_bytes().into();
+    }
+}
+
+/// Encoded header without HMAC itself. Padding isn't signed, it is not
+/// interpreted anyway.
+fn signed_part(echo: &Echo) -> Vec<u8> {
+    let mut buf = echo.encode();
+    buf.truncate(buf.len() - MAC_LEN);
+    buf
+}
+
+pub(crate) fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Nonces for requests of a client, strictly increasing even if wall clock
+/// steps back.
+pub(crate) struct Nonces {
+    last: u64,
+}
+
+impl Nonces {
+    pub(crate) fn new() -> Self {
+        Nonces { last: 0 }
+    }
+
+    pub(crate) fn next(&mut self) -> u64 {
+        self.last = now_nanos().max(self.last + 1);
+        self.last
+    }
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// Remembers nonces within `MAX_NONCE_SKEW` of our clock, like IPsec
+/// anti-replay window. Nonces outside of it are rejected, so forgetting
+/// older ones is safe.
+#[derive(Default)]
+struct ReplayWindow {
+    seen: BTreeSet<u64>,
+}
+
+impl ReplayWindow {
+    fn check(&mut self, nonce: u64, now: u64) -> std::result::Result<(), Rejection> {
+        let skew = MAX_NONCE_SKEW.as_nanos() as u64;
+        if nonce.abs_diff(now) > skew {
+            return Err(Rejection::Replayed);
+        }
+
+        let oldest = now.saturating_sub(skew);
+        while self.seen.first().is_some_and(|first| *first < oldest) {
+            self.seen.pop_first();
+        }
+
+        let too_old = self.seen.len() >= MAX_NONCES && self.seen.first().is_some_and(|first| nonce < *first);
+        if too_old || !self.seen.insert(nonce) {
+            return Err(Rejection::Replayed);
+        }
+
+        if self.seen.len() > MAX_NONCES {
+            self.seen.pop_first();
+        }
+
+        Ok(())
+    }
+}
@@ -1,7 +1,12 @@
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use clap::{Arg, ArgAction, Command};
 
+use crate::acl::Cidr;
+use crate::async_tls::{self, Fingerprint};
+use crate::output::OutputFormat;
 use crate::pinger::PING_HDR_LEN;
 
 fn cli() -> Command {
@@ -62,10 +67,76 @@ fn cli() -> Command {
                 .arg(
                     Arg::new("resp-size")
                         .long("response-size")
-                        .help("Size of echo response")
+                        .help("Size of echo response, server timestamps need 34 bytes of it [default: 34]")
                         .action(ArgAction::Set)
                         .value_parser(clap::value_parser!(u16).range(PING_HDR_LEN as i64..)),
                 )
+                .arg(
+                    Arg::new("percentiles")
+                        .long("percentiles")
+                        .help("Comma separated list of RTT percentiles to report")
+                        .action(ArgAction::Set)
+                        .value_delimiter(',')
+                        .value_parser(parse_percent)
+                        .default_value("50,90,95,99,99.9,99.99"),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .help("Output format, json prints one JSON object per line")
+                        .action(ArgAction::Set)
+                        .value_parser(["text", "json"])
+                        .default_value("text"),
+                )
+                .arg(
+                    Arg::new("csv")
+                        .long("csv")
+                        .help("Write per-packet samples to CSV file")
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("metrics-listen")
+                        .long("metrics-listen")
+                        .help("Serve Prometheus metrics over HTTP on this address")
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(SocketAddr)),
+                )
+                .arg(
+                    Arg::new("report-interval")
+                        .long("report-interval")
+                        .help("Print summary of the last interval every N seconds")
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(u64).range(1..)),
+                )
+                .arg(
+                    Arg::new("max-loss")
+                        .long("max-loss")
+                        .help("Fail if loss in percent exceeds this value")
+                        .action(ArgAction::Set)
+                        .value_parser(parse_percent),
+                )
+                .arg(
+                    Arg::new("max-rtt")
+                        .long("max-rtt")
+                        .help("Fail if p99 RTT in ms exceeds this value")
+                        .action(ArgAction::Set)
+                        .value_parser(parse_millis),
+                )
+                .arg(
+                    Arg::new("key-file")
+                        .long("key-file")
+                        .help("Sign requests and verify replies with the first key of this file")
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("tls-pin")
+                        .long("tls-pin")
+                        .help("SHA-256 fingerprint of server's TLS certificate, as the server prints it")
+                        .action(ArgAction::Set)
+                        .value_parser(async_tls::parse_fingerprint),
+                )
                 .arg(
                     Arg::new("ping-number")
                         .long("ping-number")
@@ -84,6 +155,85 @@ fn cli() -> Command {
                         .action(ArgAction::Set)
                         .required(true)
                         .value_parser(clap::value_parser!(SocketAddr)),
+                )
+                .arg(
+                    Arg::new("max-response-size")
+                        .long("max-response-size")
+                        .help("Never reply with more bytes than this, whatever client asks for")
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(u16).range(PING_HDR_LEN as i64..)),
+                )
+                .arg(
+                    Arg::new("max-ratio")
+                        .long("max-ratio")
+                        .help("Limit reply to this many times the request size, until client proves its address")
+                        .action(ArgAction::Set)
+                        .value_parser(parse_ratio),
+                )
+                .arg(
+                    Arg::new("rate-limit")
+                        .long("rate-limit")
+                        .help("Answer at most N requests per second from a single address")
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(u32).range(1..)),
+                )
+                .arg(
+                    Arg::new("key-file")
+                        .long("key-file")
+                        .help("Answer only requests signed with a key of this file")
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("tls-cert")
+                        .long("tls-cert")
+                        .help("PEM file with TLS certificate chain, self-signed one is generated and saved if it doesn't exist")
+                        .action(ArgAction::Set)
+                        .requires("tls-key")
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("tls-key")
+                        .long("tls-key")
+                        .help("PEM file with private key of TLS certificate, saved with generated certificate")
+                        .action(ArgAction::Set)
+                        .requires("tls-cert")
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("require-cookie")
+                        .long("require-cookie")
+                        .help("Send replies larger than request only to clients which echo server's cookie")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("allow")
+                        .long("allow")
+                        .help("Answer only clients from this network, may be repeated")
+                        .action(ArgAction::Append)
+                        .value_parser(clap::value_parser!(Cidr)),
+                )
+                .arg(
+                    Arg::new("deny")
+                        .long("deny")
+                        .help("Drop requests from this network, may be repeated")
+                        .action(ArgAction::Append)
+                        .value_parser(clap::value_parser!(Cidr)),
+                )
+                .arg(
+                    Arg::new("acl-file")
+                        .long("acl-file")
+                        .help("File with allow and deny rules, reloaded on SIGHUP")
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("idle-timeout")
+                        .long("idle-timeout")
+                        .help("Seconds of silence after which session of UDP client ends")
+                        .action(ArgAction::Set)
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .default_value("60"),
                 ),
         )
         .arg(
@@ -92,13 +242,54 @@ fn cli() -> Command {
                 .short('p')
                 .help("Set protocol to use for ping")
                 .action(ArgAction::Set)
-                .value_parser(["tcp", "udp", "icmp"])
+                .value_parser(["tcp", "tcp-connect", "tls", "udp", "icmp"])
                 .default_value("udp"),
         )
 }
 
+fn parse_percent(s: &str) -> Result<f64, String> {
+    let p: f64 = s.parse().map_err(|e| format!("{e}"))?;
+
+    if (0.0..=100.0).contains(&p) {
+        Ok(p)
+    } else {
+        Err(format!("{p} is not in 0..=100"))
+    }
+}
+
+fn parse_millis(s: &str) -> Result<Duration, String> {
+    let ms: f64 = s.parse().map_err(|e| format!("{e}"))?;
+
+    if ms > 0. && ms.is_finite() {
+        Ok(Duration::from_secs_f64(ms / 1000.))
+    } else {
+        Err(format!("{ms} is not a positive number of milliseconds"))
+    }
+}
+
+fn parse_ratio(s: &str) -> Result<f64, String> {
+    let ratio: f64 = s.parse().map_err(|e| format!("{e}"))?;
+
+    if ratio >= 1. && ratio.is_finite() {
+        Ok(ratio)
+    } else {
+        Err(format!("{ratio} is less than 1"))
+    }
+}
+
 pub(crate) struct ServerParams {
     pub local_address: SocketAddr,
+    pub max_response_size: Option<u16>,
+    pub max_ratio: Option<f64>,
+    pub rate_limit: Option<u32>,
+    pub require_cookie: bool,
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+    pub acl_file: Option<PathBuf>,
+    pub idle_timeout: u64,
+    pub key_file: Option<PathBuf>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
     pub protocol: String,
 }
 
@@ -111,12 +302,21 @@ pub(crate) struct PingerParams {
     pub request_size: Option<u16>,
     pub response_size: Option<u16>,
     pub ping_number: Option<u64>,
+    pub percentiles: Vec<f64>,
+    pub format: OutputFormat,
+    pub csv: Option<PathBuf>,
+    pub metrics_listen: Option<SocketAddr>,
+    pub report_interval: Option<u64>,
+    pub max_loss: Option<f64>,
+    pub max_rtt: Option<Duration>,
+    pub key_file: Option<PathBuf>,
+    pub tls_pin: Option<Fingerprint>,
     pub protocol: String,
 }
 
 pub(crate) enum CliParams {
-    ServerParams(ServerParams),
-    PingerParams(PingerParams),
+    ServerParams(Box<ServerParams>),
+    PingerParams(Box<PingerParams>),
 }
 
 pub(crate) fn get_cli_params() -> CliParams {
@@ -126,7 +326,7 @@ pub(crate) fn get_cli_params() -> CliParams {
 
     match matches.subcommand() {
         Some(("client", submatch)) => {
-            CliParams::PingerParams(PingerParams {
+            CliParams::PingerParams(Box::new(PingerParams {
                 remote_address: *submatch.get_one::<SocketAddr>("remote-address").unwrap(),
                 local_address: *submatch.get_one::<SocketAddr>("local-address").unwrap(),
                 interval: *submatch.get_one::<u64>("interval").unwrap(),
@@ -135,14 +335,37 @@ pub(crate) fn get_cli_params() -> CliParams {
                 request_size: submatch.get_one::<u16>("req-size").copied(),
                 response_size: submatch.get_one::<u16>("resp-size").copied(),
                 ping_number: submatch.get_one::<u64>("ping-number").copied(),
+                percentiles: submatch.get_many::<f64>("percentiles").unwrap().copied().collect(),
+                format: match submatch.get_one::<String>("format").unwrap().as_str() {
+                    "json" => OutputFormat::Json,
+                    _ => OutputFormat::Text,
+                },
+                csv: submatch.get_one::<PathBuf>("csv").cloned(),
+                metrics_listen: submatch.get_one::<SocketAddr>("metrics-listen").copied(),
+                report_interval: submatch.get_one::<u64>("report-interval").copied(),
+                max_loss: submatch.get_one::<f64>("max-loss").copied(),
+                max_rtt: submatch.get_one::<Duration>("max-rtt").copied(),
+                key_file: submatch.get_one::<PathBuf>("key-file").cloned(),
+                tls_pin: submatch.get_one::<Fingerprint>("tls-pin").copied(),
                 protocol: protocol.clone(),
-            })
+            }))
         }
         Some(("server", submatch)) => {
-            CliParams::ServerParams(ServerParams{
+            CliParams::ServerParams(Box::new(ServerParams {
                 local_address: *submatch.get_one::<SocketAddr>("local-address").unwrap(),
+                max_response_size: submatch.get_one::<u16>("max-response-size").copied(),
+                max_ratio: submatch.get_one::<f64>("max-ratio").copied(),
+                rate_limit: submatch.get_one::<u32>("rate-limit").copied(),
+                require_cookie: *submatch.get_one::<bool>("require-cookie").unwrap(),
+                allow: submatch.get_many::<Cidr>("allow").unwrap_or_default().copied().collect(),
+                deny: submatch.get_many::<Cidr>("deny").unwrap_or_default().copied().collect(),
+                acl_file: submatch.get_one::<PathBuf>("acl-file").cloned(),
+                idle_timeout: *submatch.get_one::<u64>("idle-timeout").unwrap(),
+                key_file: submatch.get_one::<PathBuf>("key-file").cloned(),
+                tls_cert: submatch.get_one::<PathBuf>("tls-cert").cloned(),
+                tls_key: submatch.get_one::<PathBuf>("tls-key").cloned(),
                 protocol: protocol.clone(),
-            })
+            }))
 
         }
         _ => unreachable!(),
@@ -0,0 +1,99 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use tokio::sync::mpsc::error::SendError;
+use tokio::task::JoinError;
+
+use crate::pinger::ProtocolError;
+
+/// Errors which stop a client or a server. Problems with a single packet are
+/// not errors of this kind: they are counted and logged, and the run goes on.
+#[derive(Debug)]
+pub(crate) enum Error {
+    /// I/O operation failed, `context` says which one.
+    Io { context: String, source: io::Error },
+    /// Remote side speaks a protocol we can't talk to.
+    Protocol { peer: SocketAddr, source: ProtocolError },
+    /// Remote side closed the connection.
+    ConnectionClosed(SocketAddr),
+    /// Task on the other end of a channel is gone, it reports its own error.
+    ChannelClosed,
+    /// Reader of our standard output is gone, e.g. `head` has read enough.
+    OutputClosed,
+    /// Task panicked or was cancelled.
+    Task { task: &'static str, source: JoinError },
+    /// Parameters don't make sense together.
+    Usage(String),
+    /// TLS can't be set up, e.g. certificate is malformed.
+    Tls(String),
+    /// Line `line` of configuration file `path` is malformed.
+    Config { path: PathBuf, line: usize, message: String },
+}
+
+pub(crate) type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { context, source } => write!(f, "{context}: {source}"),
+            Error::Protocol { peer, source } => write!(f, "bad reply from {peer}: {source}"),
+            Error::ConnectionClosed(peer) => write!(f, "connection closed by {peer}"),
+            Error::ChannelClosed => write!(f, "internal channel closed"),
+            Error::OutputClosed => write!(f, "output closed"),
+            Error::Task { task, source } => write!(f, "{task} failed: {source}"),
+            Error::Usage(message) => write!(f, "{message}"),
+            Error::Tls(message) => write!(f, "tls: {message}"),
+            Error::Config { path, line, message } => write!(f, "{}:{line}: {message}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Task { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl<T> From<SendError<T>> for Error {
+    fn from(_: SendError<T>) -> Self {
+        Error::ChannelClosed
+    }
+}
+
+/// Adds description of the failed operation to I/O errors.
+pub(crate) trait Context<T> {
+    fn context(self, context: &str) -> Result<T>;
+    fn with_context<F: FnOnce() -> String>(self, context: F) -> Result<T>;
+}
+
+impl<T> Context<T> for io::Result<T> {
+    fn context(self, context: &str) -> Result<T> {
+        self.with_context(|| context.to_string())
+    }
+
+    fn with_context<F: FnOnce() -> String>(self, context: F) -> Result<T> {
+        self.map_err(|source| Error::Io {
+            context: context(),
+            source,
+        })
+    }
+}
+
+/// Whether the error concerns a single packet, e.g. ICMP unreachable message
+/// reported on a socket, so that sending more packets makes sense.
+pub(crate) fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::NetworkDown
+    )
+}
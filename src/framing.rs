@@ -0,0 +1,150 @@
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::pinger::{Echo, ProtocolError, PING_HDR_LEN};
+
+/// Splits byte stream into echo frames. Every frame starts with encoded
+/// `Echo` header and its `len` field is the length of the whole frame,
+/// header included, the rest of the frame is padding.
+pub(crate) struct EchoReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> EchoReader<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        EchoReader {
+            reader,
+            buf: Vec::with_capacity(u16::MAX as usize),
+        }
+    }
+
+    /// Reads next frame from the stream, returns `None` if the stream was
+    /// closed at a frame boundary.
+    ///
+    /// This method is cancel safe: partially received frames are kept in the
+    /// internal buffer, so it can be used in `tokio::select!`.
+    pub(crate) async fn read_echo(&mut self) -> io::Result<Option<Echo>> {
+        loop {
+            if let Some(echo) = self.parse_frame()? {
+                return Ok(Some(echo));
+            }
+
+            if self.reader.read_buf(&mut self.buf).await? == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed in the middle of a frame",
+                ));
+            }
+        }
+    }
+
+    fn parse_frame(&mut self) -> io::Result<Option<Echo>> {
+        if self.buf.len() < PING_HDR_LEN {
+            return Ok(None);
+        }
+
+        let len = Echo::peek_len(&self.buf).map_err(invalid_data)? as usize;
+        if len < PING_HDR_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame length {len} is shorter than echo header"),
+            ));
+        }
+        if self.buf.len() < len {
+            return Ok(None);
+        }
+
+        let echo = Echo::decode(&self.buf[..len]).map_err(invalid_data)?;
+        self.buf.drain(..len);
+
+        Ok(Some(echo))
+    }
+}
+
+fn invalid_data(e: ProtocolError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Writes echo frame padded up to `echo.len` bytes.
+pub(crate) async fn write_echo<W: AsyncWrite + Unpin>(writer: &mut W, echo: &Echo) -> io::Result<()> {
+    let len = echo.len as usize;
+    if len < echo.header_len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("frame length {len} is shorter than echo header"),
+        ));
+    }
+
+    let mut send_buf = echo.encode();
+    send_buf.resize(len, 0);
+
+    writer.write_all(&send_buf).await?;
+    // TLS stream holds data back until it is flushed
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::duplex;
+    use tokio::time;
+
+    use super::*;
+
+    fn frame(id: u64, len: u16) -> (Echo, Vec<u8>) {
+        let echo = Echo::request(id, len, len);
+        let mut buf = echo.encode();
+        buf.resize(len as usize, 0);
+        (echo, buf)
+    }
+
+    #[tokio::test]
+    async fn split_frame() {
+        let (mut writer, reader) = duplex(4096);
+        let mut reader = EchoReader::new(reader);
+        let (echo, buf) = frame(1, 100);
+
+        writer.write_all(&buf[..10]).await.unwrap();
+        assert!(time::timeout(Duration::from_millis(10), reader.read_echo()).await.is_err());
+        writer.write_all(&buf[10..60]).await.unwrap();
+        assert!(time::timeout(Duration::from_millis(10), reader.read_echo()).await.is_err());
+        writer.write_all(&buf[60..]).await.unwrap();
+
+        assert_eq!(reader.read_echo().await.unwrap(), Some(echo));
+    }
+
+    #[tokio::test]
+    async fn coalesced_frames() {
+        let (mut writer, reader) = duplex(4096);
+        let mut reader = EchoReader::new(reader);
+        let (first, mut buf) = frame(1, 50);
+        let (second, second_buf) = frame(2, PING_HDR_LEN as u16);
+        buf.extend_from_slice(&second_buf);
+
+        writer.write_all(&buf).await.unwrap();
+        drop(writer);
+
+        assert_eq!(reader.read_echo().await.unwrap(), Some(first));
+        assert_eq!(reader.read_echo().await.unwrap(), Some(second));
+        assert_eq!(reader.read_echo().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn closed_mid_frame() {
+        let (mut writer, reader) = duplex(4096);
+        let mut reader = EchoReader::new(reader);
+        let (_, buf) = frame(1, 50);
+
+        writer.write_all(&buf[..30]).await.unwrap();
+        drop(writer);
+
+        let e = reader.read_echo().await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
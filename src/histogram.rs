@@ -0,0 +1,99 @@
+use std::time::Duration;
+
+/// Number of linear sub-buckets in every power of two range is
+/// `2^SUB_BUCKET_BITS`, which bounds relative error of recorded values.
+const SUB_BUCKET_BITS: u32 = 7;
+const SUB_BUCKET_COUNT: usize = 1 << SUB_BUCKET_BITS;
+const BUCKET_COUNT: usize = SUB_BUCKET_COUNT * (64 - SUB_BUCKET_BITS as usize + 1);
+
+/// Relative error of values reported by `Histogram`: a value is reported as
+/// the middle of its bucket, and buckets are at most `1 / 2^SUB_BUCKET_BITS`
+/// of their lower bound wide.
+pub(crate) const RELATIVE_ERROR: f64 = 1. / (2 * SUB_BUCKET_COUNT) as f64;
+
+/// HDR-style log-linear histogram of durations with nanosecond resolution.
+/// Values below `2^SUB_BUCKET_BITS` ns are stored exactly, larger ones in
+/// buckets whose width grows with the value, so memory use is fixed (about
+/// 60 KiB) no matter how many values are recorded, while every value is
+/// still known within `RELATIVE_ERROR`.
+#[derive(Clone)]
+pub(crate) struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+}
+
+impl Histogram {
+    pub(crate) fn new() -> Self {
+        Histogram {
+            counts: vec![0; BUCKET_COUNT],
+            total: 0,
+        }
+    }
+
+    pub(crate) fn record(&mut self, value: Duration) {
+        let nanos = u64::try_from(value.as_nanos()).unwrap_or(u64::MAX);
+
+        self.counts[bucket_index(nanos)] += 1;
+        self.total += 1;
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.total
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.total == 0
+    }
+
+    /// Value of the sample with zero-based `rank` in sorted order.
+    pub(crate) fn value_at_rank(&self, rank: u64) -> Duration {
+        let mut seen = 0;
+
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen > rank {
+                return Duration::from_nanos(bucket_value(index));
+            }
+        }
+
+        Duration::from_nanos(bucket_value(BUCKET_COUNT - 1))
+    }
+
+    /// Number of recorded values below `bound`.
+    pub(crate) fn count_below(&self, bound: Duration) -> u64 {
+        let nanos = u64::try_from(bound.as_nanos()).unwrap_or(u64::MAX);
+
+        self.counts
+            .iter()
+            .enumerate()
+            .take_while(|(index, _)| bucket_value(*index) < nanos)
+            .map(|(_, count)| count)
+            .sum()
+    }
+}
+
+fn bucket_index(nanos: u64) -> usize {
+    let msb = 63 - (nanos | 1).leading_zeros();
+
+    if msb < SUB_BUCKET_BITS {
+        return nanos as usize;
+    }
+
+    let shift = msb - SUB_BUCKET_BITS;
+    let sub = (nanos >> shift) as usize - SUB_BUCKET_COUNT;
+
+    SUB_BUCKET_COUNT * (shift as usize + 1) + sub
+}
+
+/// Middle of the bucket with `index`.
+fn bucket_value(index: usize) -> u64 {
+    if index < SUB_BUCKET_COUNT {
+        return index as u64;
+    }
+
+    let shift = (index / SUB_BUCKET_COUNT - 1) as u32;
+    let sub = (index % SUB_BUCKET_COUNT) as u64;
+    let lower = (SUB_BUCKET_COUNT as u64 + sub) << shift;
+
+    lower + ((1u64 << shift) >> 1)
+}
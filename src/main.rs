@@ -1,20 +1,60 @@
-use std::io;
+use std::process::ExitCode;
+use std::sync::Arc;
 use std::time::Duration;
 
+use tokio::net::TcpListener;
 use tokio::runtime;
 use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::watch;
 
-use pinger::{PingReqResp, SendMode};
+use acl::{Acl, AclConfig};
+use auth::Keys;
+use error::{Context, Error, Result};
+use metrics::Metrics;
+use output::Output;
+use policy::Policy;
+use pinger::{PingReqResp, RequestParams, SendMode, PING_HDR_LEN, TIMESTAMPS_LEN};
+use sessions::Sessions;
+use statistics::{Reports, Thresholds};
 use crate::cli::CliParams::{PingerParams, ServerParams};
 
+mod acl;
 mod async_icmp;
 mod async_tcp;
+mod async_tcp_connect;
+mod async_tls;
 mod async_udp;
+mod auth;
+mod error;
+mod framing;
+mod histogram;
+mod metrics;
+mod output;
 mod pinger;
+mod policy;
+mod sessions;
 mod statistics;
 mod cli;
 
-fn main() -> Result<(), io::Error> {
+/// Exit statuses, like ping has: replies were received and thresholds were
+/// met, no replies or thresholds exceeded, and the run failed.
+const EXIT_SUCCESS: u8 = 0;
+const EXIT_NO_REPLY: u8 = 1;
+const EXIT_ERROR: u8 = 2;
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(code) => ExitCode::from(code),
+        // Whoever reads the output has seen enough, like `rup ... | head`
+        Err(Error::OutputClosed) => ExitCode::from(EXIT_SUCCESS),
+        Err(e) => {
+            eprintln!("rup: {e}");
+            ExitCode::from(EXIT_ERROR)
+        }
+    }
+}
+
+fn run() -> Result<u8> {
     let channel_cap: usize = 32;
 
     let cli_params = cli::get_cli_params();
@@ -22,7 +62,8 @@ fn main() -> Result<(), io::Error> {
     let rt = runtime::Builder::new_current_thread()
         .enable_io()
         .enable_time()
-        .build()?;
+        .build()
+        .context("couldn't start runtime")?;
 
     match cli_params {
         PingerParams(params) => {
@@ -39,6 +80,38 @@ fn main() -> Result<(), io::Error> {
             } else {
                 (SendMode::Interval(params.interval), None)
             };
+            let (drained_send, drained_recv) = watch::channel(false);
+
+            let (request_size, response_size) = packet_sizes(&params);
+            let output = Output::new(
+                params.format,
+                params.protocol.clone(),
+                request_size,
+                response_size,
+                params.csv.as_deref(),
+            )?;
+
+            let metrics = match params.metrics_listen {
+                Some(addr) => {
+                    let listener = rt
+                        .block_on(TcpListener::bind(addr))
+                        .with_context(|| format!("metrics: couldn't listen on {addr}"))?;
+                    let metrics = Arc::new(Metrics::new(&params.remote_address.to_string(), &params.protocol));
+                    rt.spawn(metrics::serve(listener, metrics.clone()));
+                    Some(metrics)
+                }
+                None => None,
+            };
+
+            let keys = params.key_file.as_deref().map(Keys::load).transpose()?;
+            if keys.is_some() && !matches!(params.protocol.as_str(), "tcp" | "udp" | "tls") {
+                return Err(Error::Usage(format!("there is no authentication for {}", params.protocol)));
+            }
+            let request_params = RequestParams {
+                request_size: params.request_size,
+                response_size: params.response_size,
+                keys,
+            };
 
             let pinger = match params.protocol.as_str() {
                 "tcp" => rt.spawn(async_tcp::pinger_transport(
@@ -46,24 +119,45 @@ fn main() -> Result<(), io::Error> {
                     txtr_stat_send,
                     params.local_address,
                     params.remote_address,
-                    params.request_size,
-                    params.response_size,
+                    request_params,
+                    drained_recv.clone(),
                 )),
                 "udp" => rt.spawn(async_udp::pinger_transport(
                     gen_txtr_recv,
                     txtr_stat_send,
                     params.local_address,
                     params.remote_address,
-                    params.request_size,
-                    params.response_size,
+                    request_params,
+                    drained_recv.clone(),
+                )),
+                "tls" => {
+                    let pin = params
+                        .tls_pin
+                        .ok_or_else(|| Error::Usage("tls needs --tls-pin with fingerprint printed by the server".to_string()))?;
+                    rt.spawn(async_tls::pinger_transport(
+                        gen_txtr_recv,
+                        txtr_stat_send,
+                        params.local_address,
+                        params.remote_address,
+                        request_params,
+                        drained_recv.clone(),
+                        pin,
+                    ))
+                }
+                "tcp-connect" => rt.spawn(async_tcp_connect::pinger_transport(
+                    gen_txtr_recv,
+                    txtr_stat_send,
+                    params.local_address,
+                    params.remote_address,
+                    Duration::from_millis(params.wait_time),
                 )),
                 "icmp" => rt.spawn(async_icmp::pinger_transport(
                     gen_txtr_recv,
                     txtr_stat_send,
                     params.local_address,
                     params.remote_address,
-                    params.request_size,
-                    params.response_size,
+                    request_params,
+                    drained_recv.clone(),
                 )),
                 _ => unreachable!(),
             };
@@ -71,31 +165,112 @@ fn main() -> Result<(), io::Error> {
             let generator = rt.spawn(
                 pinger::generator(gen_txtr_send, send_mode, params.ping_number)
             );
+            let reports = Reports {
+                percentiles: params.percentiles,
+                output,
+                metrics,
+                interval: params.report_interval.map(Duration::from_secs),
+                thresholds: Thresholds {
+                    max_loss: params.max_loss,
+                    max_rtt: params.max_rtt,
+                },
+            };
             let statista = rt.spawn(statistics::statista(
                 txtr_stat_recv,
                 txtr_gen,
+                drained_send,
                 Duration::from_millis(params.wait_time),
+                reports,
             ));
 
-            rt.block_on(async {
-                pinger.await.unwrap();
-                generator.await.unwrap();
-                statista.await.unwrap();
-            });
+            let summary = rt.block_on(async {
+                let failed = |task| move |source| Error::Task { task, source };
+
+                let transport = pinger.await.map_err(failed("transport"));
+                let generator = generator.await.map_err(failed("generator"));
+                // Summary is printed even if transport failed, statistics
+                // errors come first, as transport fails as a consequence.
+                let summary = statista.await.map_err(failed("statistics"))??;
+                transport??;
+                generator??;
+
+                Ok::<_, Error>(summary)
+            })?;
+
+            if summary.received == 0 || !summary.violations.is_empty() {
+                return Ok(EXIT_NO_REPLY);
+            }
         }
         ServerParams(params) => {
+            let keys = params.key_file.as_deref().map(Keys::load).transpose()?;
+
+            let policy = Policy::new(
+                params.max_response_size,
+                params.max_ratio,
+                params.rate_limit,
+                params.require_cookie,
+                keys.is_some(),
+            );
+            let limited = params.max_response_size.is_some()
+                || params.max_ratio.is_some()
+                || params.rate_limit.is_some()
+                || params.require_cookie;
+
+            let acl_config = AclConfig {
+                allow: params.allow,
+                deny: params.deny,
+                file: params.acl_file,
+            };
+            let (acl_send, acl_recv) = watch::channel(acl_config.rules()?);
+            let sessions = Arc::new(Sessions::new());
+            let acl = Acl::new(acl_recv, sessions.clone());
+
             let server = match params.protocol.as_str() {
-                "tcp" => rt.spawn(async_tcp::server_transport(params.local_address)),
-                "udp" => rt.spawn(async_udp::server_transport(params.local_address)),
-                "icmp" => panic!("there is no server for icmp"),
-                _ => unreachable!(),
+                "tcp" | "tls" if limited => {
+                    // Spoofed requests can't make it through TCP handshake
+                    return Err(Error::Usage("reply limits apply to UDP server only".to_string()));
+                }
+                "tcp" => rt.spawn(async_tcp::server_transport(params.local_address, keys, acl, sessions.clone())),
+                "udp" => rt.spawn(async_udp::server_transport(
+                    params.local_address,
+                    policy,
+                    keys,
+                    acl,
+                    sessions.clone(),
+                    Duration::from_secs(params.idle_timeout),
+                )),
+                "tls" => {
+                    let config = async_tls::server_config(params.tls_cert.as_deref(), params.tls_key.as_deref())?;
+                    rt.spawn(async_tls::server_transport(params.local_address, keys, config, acl, sessions.clone()))
+                }
+                protocol => return Err(Error::Usage(format!("there is no server for {protocol}"))),
             };
 
             rt.block_on(async {
-                server.await.unwrap();
-            });
+                tokio::select! {
+                    r_val = server => r_val.map_err(|source| Error::Task { task: "server", source })?,
+                    r_val = acl::reload_on_hangup(acl_config, acl_send) => r_val,
+                    r_val = sessions::report_on_signal(sessions.clone()) => r_val,
+                }
+            })?;
+
+            sessions.print_summary();
         }
     }
 
-    Ok(())
+    Ok(EXIT_SUCCESS)
+}
+
+/// Sizes of echo request and reply payloads as they go on the wire. ICMP
+/// replies echo the request back, and there is no payload at all in
+/// tcp-connect mode.
+fn packet_sizes(params: &cli::PingerParams) -> (u16, u16) {
+    let request_size = params.request_size.unwrap_or(PING_HDR_LEN as u16);
+    let response_size = params.response_size.unwrap_or((PING_HDR_LEN + TIMESTAMPS_LEN) as u16);
+
+    match params.protocol.as_str() {
+        "tcp-connect" => (0, 0),
+        "icmp" => (request_size, request_size),
+        _ => (request_size, response_size),
+    }
 }
@@ -0,0 +1,188 @@
+use std::fmt::Write;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+
+/// Upper bounds of RTT histogram buckets in seconds, `+Inf` is implied.
+const RTT_BUCKETS: [f64; 16] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10.,
+];
+
+/// Longest HTTP request head we are willing to read.
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// How long a scraper may take to send its request. Prometheus sends it right
+/// after connecting, anything slower is a stuck or stray connection.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Live counters of the client exposed to Prometheus. They are updated by
+/// presenter together with the other outputs, so they agree with the summary.
+pub(crate) struct Metrics {
+    labels: String,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    sent: u64,
+    received: u64,
+    lost: u64,
+    last_rtt: Option<Duration>,
+    /// Non-cumulative counts of `RTT_BUCKETS`, the last one is `+Inf`.
+    buckets: [u64; RTT_BUCKETS.len() + 1],
+    rtt_sum: Duration,
+}
+
+impl Metrics {
+    pub(crate) fn new(target: &str, protocol: &str) -> Self {
+        Metrics {
+            labels: format!("target=\"{}\",protocol=\"{}\"", escape(target), escape(protocol)),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub(crate) fn sent(&self) {
+        self.state.lock().unwrap().sent += 1;
+    }
+
+    pub(crate) fn received(&self, rtt: Duration) {
+        let mut state = self.state.lock().unwrap();
+
+        state.received += 1;
+        state.last_rtt = Some(rtt);
+        state.rtt_sum += rtt;
+
+        let bucket = RTT_BUCKETS
+            .iter()
+            .position(|bound| rtt.as_secs_f64() <= *bound)
+            .unwrap_or(RTT_BUCKETS.len());
+        state.buckets[bucket] += 1;
+    }
+
+    pub(crate) fn lost(&self) {
+        self.state.lock().unwrap().lost += 1;
+    }
+
+    /// Renders metrics in Prometheus text exposition format.
+    fn render(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let labels = &self.labels;
+        let mut out = String::new();
+
+        let mut counter = |name: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            let _ = writeln!(out, "{name}{{{labels}}} {value}");
+        };
+        counter("rup_sent_total", "Echo requests sent.", state.sent);
+        counter("rup_received_total", "Replies received within wait time.", state.received);
+        counter("rup_lost_total", "Requests without reply within wait time.", state.lost);
+
+        let _ = writeln!(out, "# HELP rup_last_rtt_seconds RTT of the last reply.");
+        let _ = writeln!(out, "# TYPE rup_last_rtt_seconds gauge");
+        if let Some(rtt) = state.last_rtt {
+            let _ = writeln!(out, "rup_last_rtt_seconds{{{labels}}} {}", rtt.as_secs_f64());
+        }
+
+        let _ = writeln!(out, "# HELP rup_rtt_seconds RTT of replies.");
+        let _ = writeln!(out, "# TYPE rup_rtt_seconds histogram");
+        let mut cumulative = 0;
+        for (bound, count) in RTT_BUCKETS.iter().zip(state.buckets) {
+            cumulative += count;
+            let _ = writeln!(out, "rup_rtt_seconds_bucket{{{labels},le=\"{bound}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "rup_rtt_seconds_bucket{{{labels},le=\"+Inf\"}} {}", state.received);
+        let _ = writeln!(out, "rup_rtt_seconds_sum{{{labels}}} {}", state.rtt_sum.as_secs_f64());
+        let _ = writeln!(out, "rup_rtt_seconds_count{{{labels}}} {}", state.received);
+
+        out
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Serves metrics over HTTP to anyone who asks, on any path.
+pub(crate) async fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                eprintln!("metrics: couldn't accept connection: {e}");
+                continue;
+            }
+        };
+
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &metrics).await {
+                eprintln!("metrics: couldn't serve {addr}: {e}");
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    let mut request = Vec::with_capacity(1024);
+
+    // The request itself doesn't matter, but it must be read before answering,
+    // otherwise closing the socket with unread data resets the connection.
+    let read_request = async {
+        while !request.ends_with(b"\r\n\r\n") && request.len() < MAX_REQUEST_LEN {
+            if stream.read_buf(&mut request).await? == 0 {
+                break;
+            }
+        }
+        Ok::<_, io::Error>(())
+    };
+    time::timeout(REQUEST_TIMEOUT, read_request)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no request"))??;
+
+    let body = metrics.render();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len(),
+    );
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn scrape() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let metrics = Arc::new(Metrics::new("192.0.2.1:12345", "udp"));
+        metrics.sent();
+        metrics.sent();
+        metrics.received(Duration::from_micros(300));
+        metrics.lost();
+        tokio::spawn(serve(listener, metrics));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let labels = r#"target="192.0.2.1:12345",protocol="udp""#;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains(&format!("rup_sent_total{{{labels}}} 2\n")));
+        assert!(response.contains(&format!("rup_received_total{{{labels}}} 1\n")));
+        assert!(response.contains(&format!("rup_lost_total{{{labels}}} 1\n")));
+        assert!(response.contains(&format!("rup_rtt_seconds_bucket{{{labels},le=\"0.00025\"}} 0\n")));
+        assert!(response.contains(&format!("rup_rtt_seconds_bucket{{{labels},le=\"0.0005\"}} 1\n")));
+        assert!(response.contains(&format!("rup_rtt_seconds_bucket{{{labels},le=\"+Inf\"}} 1\n")));
+        assert!(response.contains(&format!("rup_rtt_seconds_count{{{labels}}} 1\n")));
+    }
+}
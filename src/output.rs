@@ -0,0 +1,447 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::auth::Rejection;
+use crate::error::{Context, Error, Result};
+use crate::histogram;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum OutputFormat {
+    /// Human readable lines.
+    Text,
+    /// One JSON object per line (NDJSON).
+    Json,
+}
+
+/// Where results of the client go and how they look.
+pub(crate) struct Output {
+    format: OutputFormat,
+    protocol: String,
+    request_size: u16,
+    response_size: u16,
+    csv: Option<BufWriter<File>>,
+}
+
+/// What happened to a packet, as written to CSV.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Status {
+    Reply,
+    Timeout,
+    Late,
+    Duplicate,
+}
+
+impl Status {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Status::Reply => "reply",
+            Status::Timeout => "timeout",
+            Status::Late => "late",
+            Status::Duplicate => "duplicate",
+        }
+    }
+}
+
+/// Per-packet row of CSV, fields which are unknown for the status are
+/// left empty.
+pub(crate) struct Sample {
+    pub index: u64,
+    pub sent: Option<SystemTime>,
+    pub received: Option<SystemTime>,
+    pub rtt: Option<Duration>,
+    pub status: Status,
+}
+
+const CSV_HEADER: &str = "index,sent_at,received_at,rtt_ns,status,request_size,response_size";
+
+/// Everything presenter reports, in JSON it is tagged with `event` field.
+/// Durations are in nanoseconds.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum Record {
+    Reply(ReplyRecord),
+    /// Reply arrived after a reply to a newer request, `extent` is RFC 4737
+    /// reordering extent. Follows the `Reply` record of the same `seq`.
+    Reorder { seq: u64, extent: u64 },
+    Timeout { seq: u64 },
+    Duplicate { seq: u64 },
+    Late { seq: u64 },
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+        message: String,
+    },
+    /// Reply failed authentication and was dropped, see `--key-file`.
+    Rejected { reason: Rejection },
+    /// TLS connection is set up, see `-p tls`.
+    Handshake { handshake_ns: u64 },
+    /// Statistics of the last report interval, see `--report-interval`.
+    Interval(IntervalRecord),
+    Summary(Box<Summary>),
+}
+
+/// Interval bounds are seconds since the start of the run.
+#[derive(Serialize)]
+pub(crate) struct IntervalRecord {
+    pub start_s: f64,
+    pub end_s: f64,
+    pub sent: u64,
+    pub received: u64,
+    pub lost: u64,
+    pub loss_percent: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_ns: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avg_ns: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_ns: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p99_ns: Option<u64>,
+    pub jitter_ns: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ReplyRecord {
+    pub seq: u64,
+    pub rtt_ns: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forward_ns: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reverse_ns: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_ns: Option<u64>,
+    pub jitter_ns: u64,
+    pub pdv_ns: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipdv_ns: Option<i64>,
+    pub reordered: bool,
+}
+
+#[derive(Clone, Serialize)]
+pub(crate) struct Summary {
+    pub sent: u64,
+    pub received: u64,
+    pub lost: u64,
+    pub loss_percent: f64,
+    pub duplicates: u64,
+    pub late: u64,
+    pub errors: u64,
+    pub unauthenticated: u64,
+    pub replayed: u64,
+    /// Duration of TLS handshake, it isn't included in RTT statistics.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub handshake_ns: Option<u64>,
+    pub reordered: u64,
+    pub reordered_percent: f64,
+    pub reorder_extent_max: u64,
+    pub reorder_extent_avg: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rtt: Option<RttSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delay_variation: Option<VariationSummary>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub one_way_delays: Option<OneWaySummary>,
+    /// Thresholds exceeded by the run, see `--max-loss` and `--max-rtt`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<String>,
+}
+
+#[derive(Clone, Serialize)]
+pub(crate) struct RttSummary {
+    pub min_ns: u64,
+    pub median_ns: u64,
+    pub avg_ns: u64,
+    pub std_dev_ns: u64,
+    pub max_ns: u64,
+    pub percentiles: Vec<Percentile>,
+    pub histogram: Vec<HistogramBucket>,
+}
+
+#[derive(Clone, Serialize)]
+pub(crate) struct Percentile {
+    pub p: f64,
+    pub value_ns: u64,
+}
+
+/// Number of replies with RTT below `below_ns` and not below the bound of
+/// the previous bucket.
+#[derive(Clone, Serialize)]
+pub(crate) struct HistogramBucket {
+    pub below_ns: u64,
+    pub count: u64,
+}
+
+#[derive(Clone, Serialize)]
+pub(crate) struct VariationSummary {
+    pub jitter_ns: u64,
+    pub pdv: Vec<Percentile>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipdv_min_ns: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipdv_max_ns: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipdv_avg_abs_ns: Option<u64>,
+}
+
+#[derive(Clone, Serialize)]
+pub(crate) struct OneWaySummary {
+    pub avg_forward_ns: i64,
+    pub avg_reverse_ns: i64,
+    pub avg_server_ns: u64,
+}
+
+/// JSON line: the record plus context needed to process it on its own.
+#[derive(Serialize)]
+struct Envelope<'a> {
+    #[serde(flatten)]
+    record: &'a Record,
+    timestamp_ns: u64,
+    protocol: &'a str,
+    request_size: u16,
+    response_size: u16,
+}
+
+impl Output {
+    pub(crate) fn new(
+        format: OutputFormat,
+        protocol: String,
+        request_size: u16,
+        response_size: u16,
+        csv_path: Option<&Path>,
+    ) -> Result<Self> {
+        let csv = match csv_path {
+            Some(path) => {
+                let file = File::create(path).with_context(|| format!("csv: couldn't create {}", path.display()))?;
+                let mut writer = BufWriter::new(file);
+                writeln!(writer, "{CSV_HEADER}").context("csv: couldn't write header")?;
+                Some(writer)
+            }
+            None => None,
+        };
+
+        Ok(Output {
+            format,
+            protocol,
+            request_size,
+            response_size,
+            csv,
+        })
+    }
+
+    /// Writes per-packet CSV row if CSV export is enabled. Times are UNIX
+    /// seconds with nanosecond precision.
+    pub(crate) fn sample(&mut self, sample: Sample) -> Result<()> {
+        let Some(csv) = &mut self.csv else {
+            return Ok(());
+        };
+
+        let time = |t: Option<SystemTime>| t.map(fmt_unix_time).unwrap_or_default();
+        let rtt = sample.rtt.map(|rtt| rtt.as_nanos().to_string()).unwrap_or_default();
+
+        writeln!(
+            csv,
+            "{},{},{},{rtt},{},{},{}",
+            sample.index,
+            time(sample.sent),
+            time(sample.received),
+            sample.status.as_str(),
+            self.request_size,
+            self.response_size,
+        )
+        .context("csv: couldn't write sample")
+    }
+
+    /// Flushes buffered CSV rows, must be called once the run is over.
+    pub(crate) fn finish(&mut self) -> Result<()> {
+        match &mut self.csv {
+            Some(csv) => csv.flush().context("csv: couldn't flush samples"),
+            None => Ok(()),
+        }
+    }
+
+    /// Reports `record` of an event which happened at `time`.
+    pub(crate) fn emit(&self, record: &Record, time: SystemTime) -> Result<()> {
+        let mut out = io::stdout().lock();
+
+        let written = match self.format {
+            OutputFormat::Text => print_text(&mut out, record),
+            OutputFormat::Json => {
+                let envelope = Envelope {
+                    record,
+                    timestamp_ns: time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64,
+                    protocol: &self.protocol,
+                    request_size: self.request_size,
+                    response_size: self.response_size,
+                };
+                serde_json::to_writer(&mut out, &envelope)
+                    .map_err(io::Error::from)
+                    .and_then(|_| writeln!(out))
+            }
+        };
+
+        match written {
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Err(Error::OutputClosed),
+            written => written.context("output: couldn't write"),
+        }
+    }
+}
+
+fn print_text(out: &mut impl Write, record: &Record) -> io::Result<()> {
+    match record {
+        Record::Reply(r) => {
+            let mut line = format!("seq: {} rtt: {:#?}", r.seq, ns(r.rtt_ns));
+
+            if let (Some(fwd), Some(rev), Some(srv)) = (r.forward_ns, r.reverse_ns, r.server_ns) {
+                line += &format!(" fwd: {} rev: {} srv: {:?}", fmt_signed_nanos(fwd), fmt_signed_nanos(rev), ns(srv));
+            }
+            line += &format!(" jitter: {:?} pdv: {:?}", ns(r.jitter_ns), ns(r.pdv_ns));
+            if let Some(ipdv) = r.ipdv_ns {
+                line += &format!(" ipdv: {}", fmt_signed_nanos(ipdv));
+            }
+            if r.reordered {
+                line += " reordered";
+            }
+
+            writeln!(out, "{line}")
+        }
+        // Reply line already says it is reordered
+        Record::Reorder { .. } => Ok(()),
+        Record::Timeout { seq } => writeln!(out, "seq: {seq} request timeout"),
+        Record::Duplicate { seq } => writeln!(out, "seq: {seq} duplicate reply"),
+        Record::Late { seq } => writeln!(out, "seq: {seq} late reply"),
+        Record::Error { seq: Some(seq), message } => writeln!(out, "seq: {seq} error: {message}"),
+        Record::Error { seq: None, message } => writeln!(out, "error: {message}"),
+        Record::Rejected { reason } => writeln!(out, "dropped {reason} reply"),
+        Record::Handshake { handshake_ns } => writeln!(out, "TLS handshake: {:?}", ns(*handshake_ns)),
+        Record::Interval(r) => print_interval(out, r),
+        Record::Summary(summary) => print_summary(out, summary),
+    }
+}
+
+fn print_interval(out: &mut impl Write, r: &IntervalRecord) -> io::Result<()> {
+    let mut line = format!(
+        "[{:.1}-{:.1}s] {} sent, {} received, {} lost ({:.2}% loss)",
+        r.start_s, r.end_s, r.sent, r.received, r.lost, r.loss_percent,
+    );
+    if let (Some(min), Some(avg), Some(max), Some(p99)) = (r.min_ns, r.avg_ns, r.max_ns, r.p99_ns) {
+        line += &format!(
+            ", rtt min/avg/max/p99 = {:?}/{:?}/{:?}/{:?}",
+            ns(min),
+            ns(avg),
+            ns(max),
+            ns(p99),
+        );
+    }
+    line += &format!(", jitter = {:?}", ns(r.jitter_ns));
+
+    writeln!(out, "{line}")
+}
+
+fn print_summary(out: &mut impl Write, s: &Summary) -> io::Result<()> {
+    writeln!(
+        out,
+        "\n{} sent, {} received, {} lost ({:.2}% loss), {} duplicates, {} late",
+        s.sent, s.received, s.lost, s.loss_percent, s.duplicates, s.late,
+    )?;
+    if s.errors > 0 {
+        writeln!(out, "{} errors", s.errors)?;
+    }
+    if s.unauthenticated + s.replayed > 0 {
+        writeln!(out, "{} unauthenticated, {} replayed replies dropped", s.unauthenticated, s.replayed)?;
+    }
+    if let Some(handshake) = s.handshake_ns {
+        writeln!(out, "TLS handshake = {:?}", ns(handshake))?;
+    }
+    writeln!(
+        out,
+        "{} reordered ({:.2}% reordered ratio), extent max = {}, avg = {:.2}",
+        s.reordered, s.reordered_percent, s.reorder_extent_max, s.reorder_extent_avg,
+    )?;
+
+    print_rtt_stats(out, s)?;
+
+    for violation in &s.violations {
+        writeln!(out, "\nFAILED: {violation}")?;
+    }
+
+    Ok(())
+}
+
+fn print_rtt_stats(out: &mut impl Write, s: &Summary) -> io::Result<()> {
+    let Some(rtt) = &s.rtt else {
+        return writeln!(out, "no statistics collected");
+    };
+
+    writeln!(out, "\nRTT statistics:")?;
+    writeln!(out, "min = {:?}", ns(rtt.min_ns))?;
+    writeln!(out, "med = {:?}", ns(rtt.median_ns))?;
+    writeln!(out, "avg = {:?}", ns(rtt.avg_ns))?;
+    writeln!(out, "std_dev = {:?}", ns(rtt.std_dev_ns))?;
+    writeln!(out, "max = {:?}", ns(rtt.max_ns))?;
+
+    writeln!(out, "\nRTT percentiles (within {:.1}%):", histogram::RELATIVE_ERROR * 100.)?;
+    for p in &rtt.percentiles {
+        writeln!(out, "p{} = {:?}", p.p, ns(p.value_ns))?;
+    }
+
+    print_histogram(out, &rtt.histogram)?;
+
+    if let Some(v) = &s.delay_variation {
+        writeln!(out, "\nDelay variation:")?;
+        writeln!(out, "jitter = {:?}", ns(v.jitter_ns))?;
+        for p in &v.pdv {
+            writeln!(out, "pdv p{} = {:?}", p.p, ns(p.value_ns))?;
+        }
+        if let (Some(min), Some(max), Some(avg_abs)) = (v.ipdv_min_ns, v.ipdv_max_ns, v.ipdv_avg_abs_ns) {
+            writeln!(out, "ipdv min = {}", fmt_signed_nanos(min))?;
+            writeln!(out, "ipdv max = {}", fmt_signed_nanos(max))?;
+            writeln!(out, "ipdv avg |ipdv| = {:?}", ns(avg_abs))?;
+        }
+    }
+
+    if let Some(d) = &s.one_way_delays {
+        writeln!(out, "\nOne-way delays (require synchronized clocks):")?;
+        writeln!(out, "avg fwd = {}", fmt_signed_nanos(d.avg_forward_ns))?;
+        writeln!(out, "avg rev = {}", fmt_signed_nanos(d.avg_reverse_ns))?;
+        writeln!(out, "avg srv = {:?}", ns(d.avg_server_ns))?;
+    }
+
+    Ok(())
+}
+
+fn print_histogram(out: &mut impl Write, buckets: &[HistogramBucket]) -> io::Result<()> {
+    const BAR_WIDTH: u64 = 40;
+
+    let most = buckets.iter().map(|b| b.count).max().unwrap_or(1);
+
+    writeln!(out, "\nRTT histogram:")?;
+    for b in buckets {
+        let bar = "#".repeat((b.count * BAR_WIDTH).div_ceil(most) as usize);
+        writeln!(out, "< {:>12} {:>8} {bar}", format!("{:?}", ns(b.below_ns)), b.count)?;
+    }
+
+    Ok(())
+}
+
+fn fmt_unix_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    format!("{}.{:09}", since_epoch.as_secs(), since_epoch.subsec_nanos())
+}
+
+fn ns(nanos: u64) -> Duration {
+    Duration::from_nanos(nanos)
+}
+
+fn fmt_signed_nanos(nanos: i64) -> String {
+    let abs = Duration::from_nanos(nanos.unsigned_abs());
+    if nanos < 0 {
+        format!("-{abs:?}")
+    } else {
+        format!("{abs:?}")
+    }
+}
@@ -1,19 +1,95 @@
-use std::time::{Duration, Instant};
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use serde::{Deserialize, Serialize};
-use tokio::{sync::mpsc, time};
+use tokio::{sync::{mpsc, watch}, time};
+
+use crate::auth::{Keys, Rejection};
+use crate::error::{self, Context};
 
 #[derive(Clone, Debug)]
 pub(crate) enum MsgType {
     Request,
     Response,
+    /// Transport failed to send a request or dropped a bad reply, the run
+    /// goes on. Carries index of the request if it is known.
+    Error { seq: Option<u64>, message: String },
+    /// Reply was dropped because it failed authentication.
+    Rejected(Rejection),
+    /// TLS handshake is done, it took this long.
+    Handshake(Duration),
+    /// Generator is done, no more requests will follow.
+    Finished,
 }
 
 #[derive(Clone, Debug)]
 pub(crate) struct PingReqResp {
     pub(crate) index: u64,
     pub(crate) timestamp: Instant,
+    /// Wall clock time of the same moment as `timestamp`, needed to compare
+    /// it with timestamps of the remote side.
+    pub(crate) wall_time: SystemTime,
     pub(crate) t: MsgType,
+    pub(crate) server_timestamps: Option<ServerTimestamps>,
+}
+
+impl PingReqResp {
+    pub(crate) fn error(seq: Option<u64>, message: String) -> Self {
+        PingReqResp {
+            index: seq.unwrap_or_default(),
+            timestamp: Instant::now(),
+            wall_time: SystemTime::now(),
+            t: MsgType::Error { seq, message },
+            server_timestamps: None,
+        }
+    }
+
+    pub(crate) fn rejected(rejection: Rejection) -> Self {
+        PingReqResp {
+            index: 0,
+            timestamp: Instant::now(),
+            wall_time: SystemTime::now(),
+            t: MsgType::Rejected(rejection),
+            server_timestamps: None,
+        }
+    }
+
+    pub(crate) fn handshake(duration: Duration) -> Self {
+        PingReqResp {
+            index: 0,
+            timestamp: Instant::now(),
+            wall_time: SystemTime::now(),
+            t: MsgType::Handshake(duration),
+            server_timestamps: None,
+        }
+    }
+
+    pub(crate) fn finished() -> Self {
+        PingReqResp {
+            index: 0,
+            timestamp: Instant::now(),
+            wall_time: SystemTime::now(),
+            t: MsgType::Finished,
+            server_timestamps: None,
+        }
+    }
+}
+
+/// What echo requests of a client look like.
+pub(crate) struct RequestParams {
+    pub request_size: Option<u16>,
+    pub response_size: Option<u16>,
+    /// Sign requests and verify replies with these keys.
+    pub keys: Option<Keys>,
+}
+
+impl RequestParams {
+    pub(crate) fn request(&self, index: u64) -> Echo {
+        Echo::request(
+            index,
+            self.request_size.unwrap_or(PING_HDR_LEN as u16),
+            self.response_size.unwrap_or((PING_HDR_LEN + TIMESTAMPS_LEN) as u16),
+        )
+    }
 }
 
 pub(crate) enum SendMode {
@@ -21,20 +97,375 @@ pub(crate) enum SendMode {
     Interval(u64),
 }
 
-pub const PING_HDR_LEN: usize = 8 + 2 + 2;
+/// Every echo packet starts with `PING_MAGIC`, so that stray traffic and
+/// packets of pre-versioned rup releases are not mistaken for pings.
+pub const PING_MAGIC: u16 = 0x5275;
+
+/// Protocol version this build speaks natively.
+pub const PROTOCOL_VERSION: u8 = 1;
+/// Oldest protocol version this build is still able to speak.
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+
+/// Echo header, all fields are in network byte order:
+///
+/// ```text
+///  0       2       3       4       6               14      16      18
+///  +-------+-------+-------+-------+---------------+-------+-------+
+///  | magic |version| type  | flags |      id       |  len  |resp_sz|
+///  +-------+-------+-------+-------+---------------+-------+-------+
+/// ```
+///
+/// `len` is the length of the whole packet including header and padding,
+/// `resp_size` is the length of the reply requested by the client.
+pub const PING_HDR_LEN: usize = 2 + 1 + 1 + 2 + 8 + 2 + 2;
+
+/// Set in request if the client wants server's receive and transmit
+/// timestamps, and in reply if they follow the header:
+///
+/// ```text
+///  18                      26                      34
+///  +-----------------------+-----------------------+
+///  |      receive time     |     transmit time     |
+///  +-----------------------+-----------------------+
+/// ```
+///
+/// Both are nanoseconds since UNIX epoch by server's wall clock. Server adds
+/// them only if the requested reply is large enough to hold them, so that
+/// the reply is never larger than the client asked for.
+pub const FLAG_TIMESTAMPS: u16 = 1 << 0;
+pub const TIMESTAMPS_LEN: usize = 8 + 8;
+
+/// Set if an address validation cookie follows the header, after timestamps
+/// if there are any. Server which requires cookies attaches one to every
+/// reply, the client echoes the latest one back in its requests to prove that
+/// it receives packets sent to its address.
+pub const FLAG_COOKIE: u16 = 1 << 1;
+pub const COOKIE_LEN: usize = 8;
+
+/// Set if the packet is signed with a shared key, like in TWAMP authenticated
+/// mode. Authentication block is the last optional part of the header:
+///
+/// ```text
+///  0       4               12                              44
+///  +-------+---------------+-------------------------------+
+///  |key id |     nonce     |          HMAC-SHA256          |
+///  +-------+---------------+-------------------------------+
+/// ```
+///
+/// Request nonce is client's wall clock in nanoseconds, reply carries nonce of
+/// its request. HMAC covers the whole header up to the HMAC itself.
+pub const FLAG_AUTH: u16 = 1 << 2;
+pub const AUTH_LEN: usize = 4 + 8 + MAC_LEN;
+pub const MAC_LEN: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Auth {
+    pub key_id: u32,
+    pub nonce: u64,
+    pub mac: [u8; MAC_LEN],
+}
+
+/// When the server received the request and sent the reply, like in
+/// TWAMP/STAMP test packets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct ServerTimestamps {
+    pub rx: SystemTime,
+    pub tx: SystemTime,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum EchoType {
+    Request = 1,
+    Reply = 2,
+    /// Server doesn't speak any protocol version the client does. `version`
+    /// field of such reply carries the newest version the server speaks.
+    VersionMismatch = 3,
+}
+
+impl TryFrom<u8> for EchoType {
+    type Error = ProtocolError;
 
-#[derive(Serialize, Deserialize)]
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(EchoType::Request),
+            2 => Ok(EchoType::Reply),
+            3 => Ok(EchoType::VersionMismatch),
+            t => Err(ProtocolError::UnknownType(t)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub(crate) struct Echo {
+    pub version: u8,
+    pub msg_type: EchoType,
+    /// Optional protocol features, unknown flags are ignored on reception.
+    pub flags: u16,
     pub id: u64,
     pub len: u16,
     pub resp_size: u16,
+    pub timestamps: Option<ServerTimestamps>,
+    pub cookie: Option<u64>,
+    pub auth: Option<Auth>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum ProtocolError {
+    Truncated(usize),
+    BadMagic(u16),
+    UnknownType(u8),
+    UnexpectedType(EchoType),
+    /// Remote side speaks protocol `remote` which is outside of
+    /// `MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION`.
+    VersionMismatch { remote: u8 },
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Truncated(len) => {
+                write!(f, "packet of {len} bytes is shorter than echo header")
+            }
+            ProtocolError::BadMagic(magic) => write!(
+                f,
+                "bad magic {magic:#06x}, not a rup packet or remote side runs rup without versioned protocol"
+            ),
+            ProtocolError::UnknownType(t) => write!(f, "unknown message type {t}"),
+            ProtocolError::UnexpectedType(t) => write!(f, "unexpected message type {t:?}"),
+            ProtocolError::VersionMismatch { remote } => write!(
+                f,
+                "remote side speaks protocol version {remote}, \
+                 this rup supports versions {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION}"
+            ),
+        }
+    }
+}
+
+impl Echo {
+    pub(crate) fn request(id: u64, len: u16, resp_size: u16) -> Self {
+        Echo {
+            version: PROTOCOL_VERSION,
+            msg_type: EchoType::Request,
+            flags: FLAG_TIMESTAMPS,
+            id,
+            len,
+            resp_size,
+            timestamps: None,
+            cookie: None,
+            auth: None,
+        }
+    }
+
+    /// Builds server's answer to the request received at `received_at`. The
+    /// reply is sent in the newest protocol version both sides speak: requests
+    /// from newer clients are answered in our version, and the client is
+    /// expected to fall back to it. If client's version is too old, the answer
+    /// is `VersionMismatch`.
+    ///
+    /// Transmit timestamp of the reply has to be set with `stamp_transmit`
+    /// right before sending it.
+    pub(crate) fn reply(&self, received_at: SystemTime) -> Echo {
+        if self.version < MIN_PROTOCOL_VERSION {
+            return Echo {
+                version: PROTOCOL_VERSION,
+                msg_type: EchoType::VersionMismatch,
+                flags: 0,
+                id: self.id,
+                len: PING_HDR_LEN as u16,
+                resp_size: 0,
+                timestamps: None,
+                cookie: None,
+                auth: None,
+            };
+        }
+
+        let room = self.resp_size as usize >= PING_HDR_LEN + TIMESTAMPS_LEN;
+        let (flags, timestamps) = if self.flags & FLAG_TIMESTAMPS != 0 && room {
+            let timestamps = ServerTimestamps {
+                rx: received_at,
+                tx: received_at,
+            };
+            (FLAG_TIMESTAMPS, Some(timestamps))
+        } else {
+            (0, None)
+        };
+
+        let mut reply = Echo {
+            version: self.version.min(PROTOCOL_VERSION),
+            msg_type: EchoType::Reply,
+            flags,
+            id: self.id,
+            len: 0,
+            resp_size: 0,
+            timestamps,
+            cookie: None,
+            auth: None,
+        };
+        reply.len = self.resp_size.max(reply.header_len() as u16);
+
+        reply
+    }
+
+    pub(crate) fn stamp_transmit(&mut self) {
+        if let Some(timestamps) = &mut self.timestamps {
+            timestamps.tx = SystemTime::now();
+        }
+    }
+
+    /// Attaches address validation cookie, packet grows to fit it if needed.
+    pub(crate) fn set_cookie(&mut self, cookie: u64) {
+        self.flags |= FLAG_COOKIE;
+        self.cookie = Some(cookie);
+        self.len = self.len.max(self.header_len() as u16);
+    }
+
+    /// Length of header with all its optional parts.
+    pub(crate) fn header_len(&self) -> usize {
+        let mut len = PING_HDR_LEN;
+        if self.timestamps.is_some() {
+            len += TIMESTAMPS_LEN;
+        }
+        if self.cookie.is_some() {
+            len += COOKIE_LEN;
+        }
+        if self.auth.is_some() {
+            len += AUTH_LEN;
+        }
+        len
+    }
+
+    /// Validates server's answer on the client side.
+    pub(crate) fn check_reply(&self) -> Result<(), ProtocolError> {
+        match self.msg_type {
+            EchoType::Reply if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&self.version) => Ok(()),
+            EchoType::Reply | EchoType::VersionMismatch => {
+                Err(ProtocolError::VersionMismatch { remote: self.version })
+            }
+            t => Err(ProtocolError::UnexpectedType(t)),
+        }
+    }
+
+    /// Serializes header, caller pads it up to `len` bytes.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity((self.len as usize).max(self.header_len()));
+
+        buf.extend_from_slice(&PING_MAGIC.to_be_bytes());
+        buf.push(self.version);
+        buf.push(self.msg_type as u8);
+        buf.extend_from_slice(&self.flags.to_be_bytes());
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.extend_from_slice(&self.len.to_be_bytes());
+        buf.extend_from_slice(&self.resp_size.to_be_bytes());
+
+        if let Some(timestamps) = &self.timestamps {
+            buf.extend_from_slice(&to_unix_nanos(timestamps.rx).to_be_bytes());
+            buf.extend_from_slice(&to_unix_nanos(timestamps.tx).to_be_bytes());
+        }
+        if let Some(cookie) = self.cookie {
+            buf.extend_from_slice(&cookie.to_be_bytes());
+        }
+        if let Some(auth) = &self.auth {
+            buf.extend_from_slice(&auth.key_id.to_be_bytes());
+            buf.extend_from_slice(&auth.nonce.to_be_bytes());
+            buf.extend_from_slice(&auth.mac);
+        }
+
+        buf
+    }
+
+    /// Parses header from the beginning of the packet. Packets of any
+    /// version are accepted, version negotiation is up to the caller.
+    pub(crate) fn decode(buf: &[u8]) -> Result<Echo, ProtocolError> {
+        let len = Echo::peek_len(buf)?;
+
+        let msg_type = EchoType::try_from(buf[3])?;
+        let flags = u16::from_be_bytes([buf[4], buf[5]]);
+
+        let mut offset = PING_HDR_LEN;
+
+        let timestamps = if msg_type == EchoType::Reply && flags & FLAG_TIMESTAMPS != 0 {
+            let ts = buf
+                .get(offset..offset + TIMESTAMPS_LEN)
+                .ok_or(ProtocolError::Truncated(buf.len()))?;
+            offset += TIMESTAMPS_LEN;
+            Some(ServerTimestamps {
+                rx: from_unix_nanos(u64::from_be_bytes(ts[..8].try_into().unwrap())),
+                tx: from_unix_nanos(u64::from_be_bytes(ts[8..].try_into().unwrap())),
+            })
+        } else {
+            None
+        };
+
+        let cookie = if msg_type != EchoType::VersionMismatch && flags & FLAG_COOKIE != 0 {
+            let cookie = buf
+                .get(offset..offset + COOKIE_LEN)
+                .ok_or(ProtocolError::Truncated(buf.len()))?;
+            offset += COOKIE_LEN;
+            Some(u64::from_be_bytes(cookie.try_into().unwrap()))
+        } else {
+            None
+        };
+
+        let auth = if msg_type != EchoType::VersionMismatch && flags & FLAG_AUTH != 0 {
+            let auth = buf
+                .get(offset..offset + AUTH_LEN)
+                .ok_or(ProtocolError::Truncated(buf.len()))?;
+            Some(Auth {
+                key_id: u32::from_be_bytes(auth[..4].try_into().unwrap()),
+                nonce: u64::from_be_bytes(auth[4..12].try_into().unwrap()),
+                mac: auth[12..].try_into().unwrap(),
+            })
+        } else {
+            None
+        };
+
+        Ok(Echo {
+            version: buf[2],
+            msg_type,
+            flags,
+            id: u64::from_be_bytes(buf[6..14].try_into().unwrap()),
+            len,
+            resp_size: u16::from_be_bytes([buf[16], buf[17]]),
+            timestamps,
+            cookie,
+            auth,
+        })
+    }
+
+    /// Checks magic and returns `len` field of the header, so that stream
+    /// transports know how many bytes to wait for before decoding a packet.
+    pub(crate) fn peek_len(buf: &[u8]) -> Result<u16, ProtocolError> {
+        if buf.len() < PING_HDR_LEN {
+            return Err(ProtocolError::Truncated(buf.len()));
+        }
+
+        let magic = u16::from_be_bytes([buf[0], buf[1]]);
+        if magic != PING_MAGIC {
+            return Err(ProtocolError::BadMagic(magic));
+        }
+
+        Ok(u16::from_be_bytes([buf[14], buf[15]]))
+    }
+}
+
+fn to_unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+}
+
+fn from_unix_nanos(nanos: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_nanos(nanos)
 }
 
 pub(crate) async fn generator(
     to_tx_transport: mpsc::Sender<PingReqResp>,
     mut send_mode: SendMode,
     ping_number: Option<u64>,
-) {
+) -> error::Result<()> {
+    // Signal handlers are installed once, so that a signal which comes while
+    // a request is being sent is not lost.
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
     let mut i: u64 = 0;
     loop {
         if let Some(n) = ping_number {
@@ -46,11 +477,14 @@ pub(crate) async fn generator(
         let req = PingReqResp {
             index: i,
             timestamp: Instant::now(),
+            wall_time: SystemTime::now(),
             t: MsgType::Request,
+            server_timestamps: None,
         };
 
-        if let Err(err) = to_tx_transport.send(req).await {
-            panic!("client: Error during sending {i} to transport: {err}");
+        // Transport or statistics is gone, it reports why
+        if to_tx_transport.send(req).await.is_err() {
+            return Ok(());
         }
 
         match &mut send_mode {
@@ -58,19 +492,19 @@ pub(crate) async fn generator(
                 tokio::select! {
                     r_val = channel.recv() => {
                         if r_val.is_none() {
-                            panic!("generator: cannot receive from transport");
+                            return Ok(());
                         }
                     }
-                    _ = tokio::signal::ctrl_c() => {
-                        return;
+                    r_val = &mut shutdown => {
+                        return r_val;
                     }
                 }
             }
             SendMode::Interval(interval) => {
                 tokio::select! {
                     _ = time::sleep(Duration::from_millis(*interval)) => {},
-                    _ = tokio::signal::ctrl_c() => {
-                        return;
+                    r_val = &mut shutdown => {
+                        return r_val;
                     }
                 }
 
@@ -78,4 +512,108 @@ pub(crate) async fn generator(
         }
         i += 1;
     }
+
+    Ok(())
+}
+
+/// Resolves once statistics has resolved every request after generator is
+/// done, or is gone.
+pub(crate) async fn drained(drained: &mut watch::Receiver<bool>) {
+    let _ = drained.wait_for(|drained| *drained).await;
+}
+
+/// Resolves on Ctrl-C, and on SIGTERM or SIGHUP where they exist.
+async fn shutdown_signal() -> error::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).context("generator: couldn't handle SIGTERM")?;
+        let mut hangup = signal(SignalKind::hangup()).context("generator: couldn't handle SIGHUP")?;
+
+        tokio::select! {
+            r_val = tokio::signal::ctrl_c() => r_val.context("generator: couldn't handle SIGINT"),
+            _ = terminate.recv() => Ok(()),
+            _ = hangup.recv() => Ok(()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await.context("generator: couldn't handle Ctrl-C")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply() -> Echo {
+        let mut reply = Echo::request(7, 100, 100).reply(from_unix_nanos(1_700_000_000_123_456_789));
+        reply.stamp_transmit();
+        reply
+    }
+
+    fn round_trip(echo: &Echo) -> Echo {
+        let mut buf = echo.encode();
+        assert_eq!(buf.len(), echo.header_len());
+        buf.resize(echo.len as usize, 0);
+        Echo::decode(&buf).unwrap()
+    }
+
+    #[test]
+    fn bare_header() {
+        let mut request = Echo::request(u64::MAX, PING_HDR_LEN as u16, 1500);
+        request.flags = 0;
+        assert_eq!(request.encode().len(), PING_HDR_LEN);
+        assert_eq!(round_trip(&request), request);
+    }
+
+    #[test]
+    fn timestamps() {
+        let reply = reply();
+        assert!(reply.timestamps.is_some());
+        assert_eq!(round_trip(&reply), reply);
+    }
+
+    #[test]
+    fn cookie() {
+        let mut request = Echo::request(1, PING_HDR_LEN as u16, 100);
+        request.set_cookie(0x0123_4567_89ab_cdef);
+        assert_eq!(request.len as usize, PING_HDR_LEN + COOKIE_LEN);
+        assert_eq!(round_trip(&request), request);
+
+        let mut reply = reply();
+        reply.set_cookie(42);
+        assert_eq!(round_trip(&reply), reply);
+    }
+
+    #[test]
+    fn auth() {
+        let mut reply = reply();
+        reply.set_cookie(42);
+        reply.flags |= FLAG_AUTH;
+        reply.auth = Some(Auth {
+            key_id: 3,
+            nonce: 1_700_000_000_000_000_000,
+            mac: [0xa5; MAC_LEN],
+        });
+        assert_eq!(reply.header_len(), PING_HDR_LEN + TIMESTAMPS_LEN + COOKIE_LEN + AUTH_LEN);
+        assert_eq!(round_trip(&reply), reply);
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut buf = reply().encode();
+        buf[0] = 0;
+        assert_eq!(Echo::decode(&buf), Err(ProtocolError::BadMagic(0x0075)));
+    }
+
+    #[test]
+    fn truncated() {
+        let buf = reply().encode();
+        assert_eq!(Echo::decode(&buf[..PING_HDR_LEN - 1]), Err(ProtocolError::Truncated(PING_HDR_LEN - 1)));
+        // Flagged timestamps are cut off
+        assert_eq!(Echo::decode(&buf[..PING_HDR_LEN + 8]), Err(ProtocolError::Truncated(PING_HDR_LEN + 8)));
+    }
 }
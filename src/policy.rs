@@ -0,0 +1,200 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::mem;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::pinger::{Echo, EchoType, AUTH_LEN, COOKIE_LEN, FLAG_TIMESTAMPS, PING_HDR_LEN, TIMESTAMPS_LEN};
+
+/// How long a cookie stays valid. Cookies of the previous period are still
+/// accepted, so a cookie lives between one and two periods.
+const COOKIE_PERIOD: Duration = Duration::from_secs(60);
+
+/// Most sources rate limiter keeps track of, which keeps its memory to a few
+/// megabytes. A source forgotten early only gets a full bucket again.
+const MAX_SOURCES: usize = 65536;
+
+/// Limits which keep UDP server from being used as a DDoS reflector: spoofed
+/// request must not trigger a reply much larger than itself, or more replies
+/// than a real client would ask for.
+pub(crate) struct Policy {
+    max_response_size: u16,
+    max_ratio: Option<f64>,
+    rate_limit: Option<RateLimiter>,
+    cookies: Option<Cookies>,
+    /// Room taken by authentication block, which is added after shaping.
+    signature_len: usize,
+}
+
+impl Policy {
+    /// Requests of clients which haven't proven their address with a cookie
+    /// get replies of at most `max_ratio` times their size, or of the size of
+    /// request if cookies are required and there is no ratio set. Replies
+    /// which are going to be `signed` fit in the limits with their signature.
+    pub(crate) fn new(
+        max_response_size: Option<u16>,
+        max_ratio: Option<f64>,
+        rate_limit: Option<u32>,
+        require_cookie: bool,
+        signed: bool,
+    ) -> Self {
+        Policy {
+            max_response_size: max_response_size.unwrap_or(u16::MAX),
+            max_ratio: max_ratio.or(require_cookie.then_some(1.)),
+            rate_limit: rate_limit.map(RateLimiter::new),
+            cookies: require_cookie.then(Cookies::new),
+            signature_len: if signed { AUTH_LEN } else { 0 },
+        }
+    }
+
+    /// Whether request from `addr` may be answered at all.
+    pub(crate) fn admit(&mut self, addr: IpAddr) -> bool {
+        match &mut self.rate_limit {
+            Some(rate_limit) => rate_limit.admit(addr, Instant::now()),
+            None => true,
+        }
+    }
+
+    /// Shrinks `reply` to request `req` of `req_len` bytes on the wire down to
+    /// what the policy allows, and attaches a fresh cookie to it. Only the
+    /// bare header, signed if the server signs replies, is sent whatever the
+    /// limit, optional parts go in if they fit: cookie first, as the client
+    /// can't prove its address without it, then timestamps.
+    pub(crate) fn shape(&self, req: &Echo, req_len: usize, addr: IpAddr, reply: &mut Echo) {
+        if reply.msg_type != EchoType::Reply {
+            return;
+        }
+
+        let now = Instant::now();
+        let proven = self
+            .cookies
+            .as_ref()
+            .is_some_and(|cookies| req.cookie.is_some_and(|cookie| cookies.check(addr, cookie, now)));
+
+        let mut limit = self.max_response_size as usize;
+        if let (false, Some(ratio)) = (proven, self.max_ratio) {
+            limit = limit.min((req_len as f64 * ratio) as usize);
+        }
+        let limit = limit.max(PING_HDR_LEN + self.signature_len);
+        let header_len = |reply: &Echo| reply.header_len() + self.signature_len;
+
+        let timestamps = reply.timestamps.take();
+        reply.flags &= !FLAG_TIMESTAMPS;
+
+        if let Some(cookies) = &self.cookies {
+            if header_len(reply) + COOKIE_LEN <= limit {
+                reply.set_cookie(cookies.issue(addr, now));
+            }
+        }
+        if timestamps.is_some() && header_len(reply) + TIMESTAMPS_LEN <= limit {
+            reply.timestamps = timestamps;
+            reply.flags |= FLAG_TIMESTAMPS;
+        }
+
+        reply.len = (req.resp_size as usize).min(limit).max(header_len(reply)) as u16;
+    }
+}
+
+/// Token bucket per source address, with burst of one second worth of
+/// requests. Buckets are kept in two generations of up to half of
+/// `MAX_SOURCES` each: once the current one is full, the previous one is
+/// forgotten. So a flood of spoofed sources costs amortized O(1) per request
+/// and never locks new sources out, and active sources stay in the current
+/// generation.
+struct RateLimiter {
+    rate: f64,
+    current: HashMap<IpAddr, Bucket>,
+    previous: HashMap<IpAddr, Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Source was reported as limited, and hasn't calmed down since.
+    limited: bool,
+}
+
+impl RateLimiter {
+    fn new(rate: u32) -> Self {
+        RateLimiter {
+            rate: rate as f64,
+            current: HashMap::new(),
+            previous: HashMap::new(),
+        }
+    }
+
+    fn admit(&mut self, addr: IpAddr, now: Instant) -> bool {
+        let rate = self.rate;
+
+        if !self.current.contains_key(&addr) {
+            let bucket = self.previous.remove(&addr).unwrap_or(Bucket {
+                tokens: rate,
+                updated: now,
+                limited: false,
+            });
+            if self.current.len() >= MAX_SOURCES / 2 {
+                self.previous = mem::take(&mut self.current);
+            }
+            self.current.insert(addr, bucket);
+        }
+        let bucket = self.current.get_mut(&addr).expect("bucket was just inserted");
+
+        bucket.tokens = (bucket.tokens + (now - bucket.updated).as_secs_f64() * rate).min(rate);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            if bucket.tokens + 1. >= rate {
+                bucket.limited = false;
+            }
+            true
+        } else {
+            if !bucket.limited {
+                println!("Rate limiting {addr}: more than {rate} requests per second");
+                bucket.limited = true;
+            }
+            false
+        }
+    }
+}
+
+/// Stateless address validation: a cookie is a keyed hash of client address
+/// and current period, so the server doesn't keep anything per client.
+struct Cookies {
+    key: RandomState,
+    started: Instant,
+}
+
+impl Cookies {
+    fn new() -> Self {
+        Cookies {
+            key: RandomState::new(),
+            started: Instant::now(),
+        }
+    }
+
+    fn period(&self, now: Instant) -> u64 {
+        (now - self.started).as_secs() / COOKIE_PERIOD.as_secs()
+    }
+
+    fn cookie(&self, addr: IpAddr, period: u64) -> u64 {
+        let mut hasher = self.key.build_hasher();
+        match addr {
+            IpAddr::V4(ip) => hasher.write(&ip.octets()),
+            IpAddr::V6(ip) => hasher.write(&ip.octets()),
+        }
+        hasher.write_u64(period);
+        hasher.finish()
+    }
+
+    fn issue(&self, addr: IpAddr, now: Instant) -> u64 {
+        self.cookie(addr, self.period(now))
+    }
+
+    fn check(&self, addr: IpAddr, cookie: u64, now: Instant) -> bool {
+        let period = self.period(now);
+        cookie == self.cookie(addr, period)
+            || period.checked_sub(1).is_some_and(|previous| cookie == self.cookie(addr, previous))
+    }
+}
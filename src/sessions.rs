@@ -0,0 +1,339 @@
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::auth::{Rejection, Rejections};
+use crate::error::{Context, Result};
+
+/// Most clients tracked at once. UDP sessions live until they are idle for
+/// `--idle-timeout`, so a flood of requests from spoofed addresses would
+/// otherwise hold a session for each of them that long.
+const MAX_SESSIONS: usize = 65536;
+
+/// How far behind the newest request a missing one may still come. Requests
+/// further behind or ahead are taken for a restart of the client, which also
+/// keeps ids forged by anyone from counting billions of requests lost.
+const MAX_REORDER: u64 = 1024;
+
+/// Counters of a single client: UDP source address or TCP connection.
+struct Session {
+    first_seen: Instant,
+    last_seen: Instant,
+    requests: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    /// Id the next request is expected to have.
+    next_id: u64,
+    /// Ids within `MAX_REORDER` behind `next_id` which haven't come yet.
+    missing: BTreeSet<u64>,
+    /// Requests missing from the sequence of ids, which were lost on the way
+    /// to the server, or dropped by it before they were decoded.
+    lost: u64,
+    reordered: u64,
+    duplicates: u64,
+}
+
+impl Session {
+    fn new(id: u64, now: Instant) -> Self {
+        // Requests sent before the first one which made it are not counted,
+        // client could have been pinging someone else
+        Session {
+            first_seen: now,
+            last_seen: now,
+            requests: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            next_id: id,
+            missing: BTreeSet::new(),
+            lost: 0,
+            reordered: 0,
+            duplicates: 0,
+        }
+    }
+
+    fn record(&mut self, id: u64, bytes_in: usize, bytes_out: usize, now: Instant) {
+        self.last_seen = now;
+        self.requests += 1;
+        self.bytes_in += bytes_in as u64;
+        self.bytes_out += bytes_out as u64;
+
+        if id >= self.next_id && id - self.next_id <= MAX_REORDER {
+            self.lost = self.lost.saturating_add(id - self.next_id);
+            self.missing.extend(self.next_id..id);
+            self.next_id = id.saturating_add(1);
+
+            let oldest = self.next_id.saturating_sub(MAX_REORDER);
+            while self.missing.first().is_some_and(|first| *first < oldest) {
+                self.missing.pop_first();
+            }
+        } else if self.missing.remove(&id) {
+            // Request which was taken for lost came after all
+            self.lost -= 1;
+            self.reordered += 1;
+        } else if id.abs_diff(self.next_id) > MAX_REORDER {
+            // Client starts over, what it lost before stays lost
+            self.missing.clear();
+            self.next_id = id.saturating_add(1);
+        } else {
+            self.duplicates += 1;
+        }
+    }
+
+    fn describe(&self, now: Instant) -> String {
+        format!(
+            "{} requests, {} bytes in, {} bytes out, {} lost ({:.2}% loss), {} reordered, {} duplicates, \
+             first seen {:.1}s ago, last seen {:.1}s ago",
+            self.requests,
+            self.bytes_in,
+            self.bytes_out,
+            self.lost,
+            loss_percent(self.lost, self.requests - self.duplicates),
+            self.reordered,
+            self.duplicates,
+            (now - self.first_seen).as_secs_f64(),
+            (now - self.last_seen).as_secs_f64(),
+        )
+    }
+}
+
+fn loss_percent(lost: u64, received: u64) -> f64 {
+    match lost + received {
+        0 => 0.,
+        total => lost as f64 * 100. / total as f64,
+    }
+}
+
+/// Server side statistics: live sessions of clients, totals of the finished
+/// ones and requests dropped before they got to any session. Shared between
+/// server tasks, so it is printed on signal whatever state they are in.
+pub(crate) struct Sessions {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    live: HashMap<SocketAddr, Session>,
+    finished: Totals,
+    /// Requests answered while the table was full.
+    untracked: u64,
+    acl_rejected: u64,
+    rate_limited: u64,
+    rejections: Rejections,
+}
+
+#[derive(Clone, Default)]
+struct Totals {
+    sessions: u64,
+    requests: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    lost: u64,
+    reordered: u64,
+    duplicates: u64,
+}
+
+impl Totals {
+    fn add(&mut self, session: &Session) {
+        self.sessions += 1;
+        self.requests += session.requests;
+        self.bytes_in += session.bytes_in;
+        self.bytes_out += session.bytes_out;
+        self.lost += session.lost;
+        self.reordered += session.reordered;
+        self.duplicates += session.duplicates;
+    }
+}
+
+impl Sessions {
+    pub(crate) fn new() -> Self {
+        Sessions {
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Counts answered request `id` of `bytes_in` bytes and its reply of
+    /// `bytes_out` bytes. Returns whether `addr` is a new client.
+    pub(crate) fn record(&self, addr: SocketAddr, id: u64, bytes_in: usize, bytes_out: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        if let Some(session) = state.live.get_mut(&addr) {
+            session.record(id, bytes_in, bytes_out, now);
+            return false;
+        }
+        if state.live.len() >= MAX_SESSIONS {
+            state.untracked += 1;
+            return false;
+        }
+
+        let mut session = Session::new(id, now);
+        session.record(id, bytes_in, bytes_out, now);
+        state.live.insert(addr, session);
+        true
+    }
+
+    /// Ends session of `addr`, when its connection is closed.
+    pub(crate) fn close(&self, addr: SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(session) = state.live.remove(&addr) {
+            println!("Session of {addr} closed: {}", session.describe(Instant::now()));
+            state.finished.add(&session);
+        }
+    }
+
+    /// Ends sessions of clients which sent nothing for `idle` time. UDP has
+    /// no other way to tell the client is gone.
+    pub(crate) fn expire(&self, idle: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        let expired: Vec<SocketAddr> = state
+            .live
+            .iter()
+            .filter(|(_, session)| now - session.last_seen >= idle)
+            .map(|(addr, _)| *addr)
+            .collect();
+
+        for addr in expired {
+            if let Some(session) = state.live.remove(&addr) {
+                println!("Session of {addr} expired: {}", session.describe(now));
+                state.finished.add(&session);
+            }
+        }
+    }
+
+    /// Counts request rejected by ACL and returns how many there were.
+    pub(crate) fn acl_rejected(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.acl_rejected += 1;
+        state.acl_rejected
+    }
+
+    pub(crate) fn rate_limited(&self) {
+        self.state.lock().unwrap().rate_limited += 1;
+    }
+
+    /// Counts `rejection` and returns how many of its kind there were.
+    pub(crate) fn rejected(&self, rejection: Rejection) -> u64 {
+        self.state.lock().unwrap().rejections.count(rejection)
+    }
+
+    /// Prints live sessions, then totals of all sessions, live or finished.
+    pub(crate) fn print_summary(&self) {
+        let state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        println!("\n--- server statistics ---");
+
+        let mut live: Vec<_> = state.live.iter().collect();
+        live.sort_by_key(|(_, session)| session.first_seen);
+        for (addr, session) in &live {
+            println!("{addr}: {}", session.describe(now));
+        }
+
+        let mut totals = state.finished.clone();
+        for (_, session) in &live {
+            totals.add(session);
+        }
+
+        println!(
+            "{} sessions ({} live), {} requests, {} bytes in, {} bytes out",
+            totals.sessions,
+            live.len(),
+            totals.requests + state.untracked,
+            totals.bytes_in,
+            totals.bytes_out,
+        );
+        println!(
+            "{} lost ({:.2}% loss), {} reordered, {} duplicates on the way to server",
+            totals.lost,
+            loss_percent(totals.lost, totals.requests - totals.duplicates),
+            totals.reordered,
+            totals.duplicates,
+        );
+        if state.untracked > 0 {
+            println!("{} requests of untracked clients, session table was full", state.untracked);
+        }
+        println!(
+            "{} rejected by ACL, {} rate limited, {} unauthenticated, {} replayed requests dropped",
+            state.acl_rejected, state.rate_limited, state.rejections.unauthenticated, state.rejections.replayed,
+        );
+    }
+}
+
+/// Prints summary on SIGUSR1 and keeps going, resolves on Ctrl-C or SIGTERM.
+pub(crate) async fn report_on_signal(sessions: Arc<Sessions>) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).context("server: couldn't handle SIGTERM")?;
+        let mut report = signal(SignalKind::user_defined1()).context("server: couldn't handle SIGUSR1")?;
+
+        loop {
+            tokio::select! {
+                r_val = tokio::signal::ctrl_c() => return r_val.context("server: couldn't handle SIGINT"),
+                _ = terminate.recv() => return Ok(()),
+                _ = report.recv() => sessions.print_summary(),
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = sessions;
+        tokio::signal::ctrl_c().await.context("server: couldn't handle Ctrl-C")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(ids: &[u64]) -> Session {
+        let now = Instant::now();
+        let mut session = Session::new(ids[0], now);
+        for id in ids {
+            session.record(*id, 18, 34, now);
+        }
+        session
+    }
+
+    #[test]
+    fn gap() {
+        let session = session(&[10, 11, 14, 15]);
+        assert_eq!((session.requests, session.lost, session.reordered, session.duplicates), (4, 2, 0, 0));
+        assert_eq!(session.missing, BTreeSet::from([12, 13]));
+    }
+
+    #[test]
+    fn late_fill() {
+        let session = session(&[10, 13, 12, 14, 11]);
+        assert_eq!((session.lost, session.reordered, session.duplicates), (0, 2, 0));
+        assert!(session.missing.is_empty());
+    }
+
+    #[test]
+    fn duplicate() {
+        let session = session(&[10, 11, 11, 12, 10]);
+        assert_eq!((session.lost, session.reordered, session.duplicates), (0, 0, 2));
+    }
+
+    #[test]
+    fn restart() {
+        // Backward and forward jumps beyond the window start over, later gaps
+        // are counted from the new sequence
+        let session = session(&[5000, 5002, 0, 2, 1, 100_000, 100_001, 100_001]);
+        assert_eq!((session.lost, session.reordered, session.duplicates), (1, 1, 1));
+        assert_eq!(session.next_id, 100_002);
+    }
+
+    #[test]
+    fn overflow() {
+        let session = session(&[0, u64::MAX - 2, u64::MAX]);
+        assert_eq!((session.requests, session.lost), (3, 1));
+        assert_eq!(session.next_id, u64::MAX);
+    }
+}
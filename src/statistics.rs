@@ -1,162 +1,909 @@
 use std::sync::Arc;
-use std::time::Duration;
-use std::{cmp::Ordering, collections::VecDeque};
+use std::time::{Duration, Instant, SystemTime};
+use std::collections::{BTreeMap, VecDeque};
 
-use tokio::sync::mpsc::{self, Receiver, Sender};
-use tokio::sync::Mutex;
-use tokio::time::sleep;
+use tokio::sync::mpsc::{self, Receiver, Sender, WeakSender};
+use tokio::sync::{watch, Mutex};
+use tokio::time::{self, sleep, Interval};
 
+use crate::auth::Rejection;
+use crate::error::{Error, Result};
+use crate::histogram::Histogram;
+use crate::metrics::Metrics;
+use crate::output::{
+    HistogramBucket, IntervalRecord, OneWaySummary, Output, Percentile, Record, ReplyRecord, RttSummary, Sample, Status, Summary,
+    VariationSummary,
+};
 use crate::pinger::{MsgType, PingReqResp};
 
 #[derive(Debug)]
 struct PingRTT {
     index: u64,
     rtt: Duration,
+    /// Wall clock times the request was sent and the reply was received.
+    sent: SystemTime,
+    received: SystemTime,
+    delays: Option<OneWayDelays>,
+}
+
+/// RTT split by server's timestamps. Forward and reverse delays compare wall
+/// clocks of both sides, so they are only meaningful if the clocks are
+/// synchronized, and may even be negative otherwise.
+#[derive(Debug)]
+struct OneWayDelays {
+    forward: i64,
+    reverse: i64,
+    server: Duration,
+}
+
+impl OneWayDelays {
+    fn new(req: &PingReqResp, resp: &PingReqResp) -> Option<Self> {
+        let server = resp.server_timestamps?;
+
+        Some(OneWayDelays {
+            forward: signed_nanos(req.wall_time, server.rx),
+            reverse: signed_nanos(server.tx, resp.wall_time),
+            server: server.tx.duration_since(server.rx).unwrap_or_default(),
+        })
+    }
+}
+
+/// Nanoseconds from `earlier` to `later`, negative if `later` is before
+/// `earlier`.
+fn signed_nanos(earlier: SystemTime, later: SystemTime) -> i64 {
+    match later.duration_since(earlier) {
+        Ok(d) => d.as_nanos() as i64,
+        Err(e) => -(e.duration().as_nanos() as i64),
+    }
+}
+
+fn nanos(d: Duration) -> u64 {
+    u64::try_from(d.as_nanos()).unwrap_or(u64::MAX)
+}
+
+/// What statista tells presenter about.
+#[derive(Debug)]
+enum PingEvent {
+    Sent,
+    Reply(PingRTT),
+    /// No reply came within wait time.
+    Lost { index: u64, sent: SystemTime },
+    /// Reply to a request which was already answered.
+    Duplicate { index: u64 },
+    /// Reply to a request which was already counted as lost.
+    Late { index: u64 },
+    /// Transport problem which didn't stop the run.
+    Error { seq: Option<u64>, message: String, time: SystemTime },
+    /// Reply failed authentication and was dropped.
+    Rejected { rejection: Rejection, time: SystemTime },
+    /// Connection is set up, TLS handshake took `duration`.
+    Handshake { duration: Duration, time: SystemTime },
+}
+
+/// How many resolved requests are remembered to tell duplicate replies from
+/// late ones. Replies to older requests are counted as late.
+const RESOLVED_HISTORY: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Resolution {
+    Answered,
+    Lost,
+    AnsweredLate,
+}
+
+/// Requests waiting for reply by their index and recent history of resolved
+/// ones. Every request stays here until it gets its own reply or its own wait
+/// time expires, so replies may come in any order.
+struct Pending {
+    requests: BTreeMap<u64, PingReqResp>,
+    resolved: BTreeMap<u64, Resolution>,
+    /// Index following the newest request, replies to anything newer are
+    /// bogus.
+    next_index: u64,
+    /// Generator is done, so no new requests will come.
+    finished: bool,
+    /// Tells transport that every request is resolved after generator is done.
+    drained: watch::Sender<bool>,
+}
+
+impl Pending {
+    fn new(drained: watch::Sender<bool>) -> Self {
+        Pending {
+            requests: BTreeMap::new(),
+            resolved: BTreeMap::new(),
+            next_index: 0,
+            finished: false,
+            drained,
+        }
+    }
+
+    fn check_drained(&self) {
+        if self.finished && self.requests.is_empty() {
+            self.drained.send_replace(true);
+        }
+    }
+
+    fn resolve(&mut self, index: u64, resolution: Resolution) {
+        self.resolved.insert(index, resolution);
+
+        if self.resolved.len() > RESOLVED_HISTORY {
+            self.resolved.pop_first();
+        }
+    }
 }
 
 async fn receive_timeout(
     index: u64,
-    req_mutex: Arc<Mutex<VecDeque<PingReqResp>>>,
+    req_mutex: Arc<Mutex<Pending>>,
     wait_time: Duration,
     to_generator: Option<Sender<u8>>,
+    to_presenter: WeakSender<PingEvent>,
 ) {
     sleep(wait_time).await;
 
-    let mut requests = req_mutex.lock().await;
+    // Statista resolves whatever is left itself once it is done
+    let Some(to_presenter) = to_presenter.upgrade() else {
+        return;
+    };
+
+    let mut pending = req_mutex.lock().await;
 
-    while let Some(req) = requests.front() {
-        if req.index <= index {
-            requests.pop_front();
-            println!("seq: {index} request timeout");
+    if let Some(req) = pending.requests.remove(&index) {
+        pending.resolve(index, Resolution::Lost);
+        pending.check_drained();
 
-            if let Some(gen_channel) = &to_generator {
-                gen_channel.send(0).await.unwrap();
+        // Presenter is only gone if it failed, statista reports why
+        let _ = to_presenter.send(PingEvent::Lost { index, sent: req.wall_time }).await;
+
+        // Generator may be already gone if the run was interrupted
+        if let Some(gen_channel) = &to_generator {
+            let _ = gen_channel.send(0).await;
+        }
+    }
+}
+
+/// What presenter reports and where.
+pub(crate) struct Reports {
+    pub percentiles: Vec<f64>,
+    pub output: Output,
+    pub metrics: Option<Arc<Metrics>>,
+    /// Period of interval summaries, if they are wanted.
+    pub interval: Option<Duration>,
+    pub thresholds: Thresholds,
+}
+
+/// Limits which fail the run if exceeded.
+#[derive(Default)]
+pub(crate) struct Thresholds {
+    /// Maximal loss in percent.
+    pub max_loss: Option<f64>,
+    /// Maximal p99 RTT.
+    pub max_rtt: Option<Duration>,
+}
+
+impl Thresholds {
+    fn violations(&self, counters: &Counters, sequence: &RttSequence) -> Vec<String> {
+        let mut violations = Vec::new();
+
+        if let Some(max_loss) = self.max_loss {
+            let loss = counters.loss_percent();
+            if loss > max_loss {
+                violations.push(format!("loss {loss:.2}% exceeds {max_loss}%"));
+            }
+        }
+
+        if let Some(max_rtt) = self.max_rtt {
+            if !sequence.hist.is_empty() {
+                let p99 = sequence.percentile(99.);
+                if p99 > max_rtt {
+                    violations.push(format!("p99 RTT {p99:?} exceeds {max_rtt:?}"));
+                }
             }
-        } else {
-            break;
         }
+
+        violations
     }
 }
 
 pub(crate) async fn statista(
     mut from_transport: Receiver<PingReqResp>,
     to_generator: Option<Sender<u8>>,
+    drained: watch::Sender<bool>,
     wait_time: Duration,
-) {
-    let req_lock = Arc::new(Mutex::new(VecDeque::<PingReqResp>::new()));
-    let (stat_pres_send, stat_pres_recv): (Sender<PingRTT>, Receiver<PingRTT>) = mpsc::channel(32);
+    reports: Reports,
+) -> Result<Summary> {
+    let req_lock = Arc::new(Mutex::new(Pending::new(drained)));
+    let (stat_pres_send, stat_pres_recv): (Sender<PingEvent>, Receiver<PingEvent>) = mpsc::channel(32);
 
-    tokio::spawn(presenter(stat_pres_recv));
+    let presenter = tokio::spawn(presenter(stat_pres_recv, reports));
 
-    loop {
-        if let Some(resp) = from_transport.recv().await {
-            match resp.t {
-                MsgType::Request => {
-                    tokio::spawn(receive_timeout(
-                        resp.index,
-                        req_lock.clone(),
-                        wait_time,
-                        to_generator.clone(),
-                    ));
-
-                    let mut requests = req_lock.lock().await;
-
-                    requests.push_back(resp);
-                }
-                MsgType::Response => {
-                    let index = resp.index;
-
-                    let mut requests = req_lock.lock().await;
-
-                    while let Some(req) = requests.pop_front() {
-                        match index.cmp(&req.index) {
-                            Ordering::Greater => {
-                                println!("seq: {index} response reordering or loss");
-                                continue;
-                            }
-                            Ordering::Equal => {
-                                let timestamp = PingRTT {
-                                    index,
-                                    rtt: resp.timestamp.duration_since(req.timestamp),
-                                };
-
-                                if let Some(gen_channel) = &to_generator {
-                                    gen_channel.send(0).await.unwrap();
-                                }
-
-                                stat_pres_send.send(timestamp).await.unwrap();
-                            }
-                            Ordering::Less => requests.push_front(req),
+    let dispatched = dispatch(&mut from_transport, &to_generator, wait_time, &req_lock, &stat_pres_send).await;
+
+    // Transport is gone, so replies to requests still waiting will never be
+    // received. Timeouts are not waited for, they find no presenter to tell.
+    let mut pending = req_lock.lock().await;
+    while let Some((index, req)) = pending.requests.pop_first() {
+        pending.resolve(index, Resolution::Lost);
+        let _ = stat_pres_send.send(PingEvent::Lost { index, sent: req.wall_time }).await;
+    }
+    drop(pending);
+
+    drop(stat_pres_send);
+    let summary = presenter
+        .await
+        .map_err(|source| Error::Task { task: "presenter", source })??;
+    dispatched?;
+
+    Ok(summary)
+}
+
+/// Matches replies with requests and tells presenter what happened, until
+/// transport is done.
+async fn dispatch(
+    from_transport: &mut Receiver<PingReqResp>,
+    to_generator: &Option<Sender<u8>>,
+    wait_time: Duration,
+    req_lock: &Arc<Mutex<Pending>>,
+    to_presenter: &Sender<PingEvent>,
+) -> Result<()> {
+    while let Some(resp) = from_transport.recv().await {
+        match resp.t {
+            MsgType::Request => {
+                tokio::spawn(receive_timeout(
+                    resp.index,
+                    req_lock.clone(),
+                    wait_time,
+                    to_generator.clone(),
+                    to_presenter.downgrade(),
+                ));
+
+                to_presenter.send(PingEvent::Sent).await?;
+
+                let mut pending = req_lock.lock().await;
+
+                pending.next_index = pending.next_index.max(resp.index + 1);
+                pending.requests.insert(resp.index, resp);
+            }
+            MsgType::Response => {
+                let index = resp.index;
+
+                let mut pending = req_lock.lock().await;
+
+                let Some(req) = pending.requests.remove(&index) else {
+                    let event = match pending.resolved.get(&index) {
+                        Some(Resolution::Answered | Resolution::AnsweredLate) => PingEvent::Duplicate { index },
+                        // Requests older than the history were resolved too
+                        Some(Resolution::Lost) | None if index < pending.next_index => {
+                            pending.resolve(index, Resolution::AnsweredLate);
+                            PingEvent::Late { index }
                         }
-                        break;
-                    }
+                        _ => PingEvent::Error {
+                            seq: Some(index),
+                            message: format!("reply to request {index} which was never sent"),
+                            time: resp.wall_time,
+                        },
+                    };
+                    to_presenter.send(event).await?;
+                    continue;
+                };
+
+                let timestamp = PingRTT {
+                    index,
+                    rtt: resp.timestamp.duration_since(req.timestamp),
+                    sent: req.wall_time,
+                    received: resp.wall_time,
+                    delays: OneWayDelays::new(&req, &resp),
+                };
+
+                pending.resolve(index, Resolution::Answered);
+                pending.check_drained();
+
+                if let Some(gen_channel) = to_generator {
+                    let _ = gen_channel.send(0).await;
                 }
+
+                to_presenter.send(PingEvent::Reply(timestamp)).await?;
+            }
+            MsgType::Error { seq, message } => {
+                let event = PingEvent::Error {
+                    seq,
+                    message,
+                    time: resp.wall_time,
+                };
+                to_presenter.send(event).await?;
+            }
+            MsgType::Rejected(rejection) => {
+                let event = PingEvent::Rejected {
+                    rejection,
+                    time: resp.wall_time,
+                };
+                to_presenter.send(event).await?;
+            }
+            MsgType::Handshake(duration) => {
+                let event = PingEvent::Handshake {
+                    duration,
+                    time: resp.wall_time,
+                };
+                to_presenter.send(event).await?;
+            }
+            MsgType::Finished => {
+                let mut pending = req_lock.lock().await;
+
+                pending.finished = true;
+                pending.check_drained();
             }
-        } else {
-            return;
         }
     }
+
+    Ok(())
 }
 
-async fn presenter(mut from_statista: Receiver<PingRTT>) {
+async fn presenter(mut from_statista: Receiver<PingEvent>, reports: Reports) -> Result<Summary> {
+    let Reports {
+        percentiles,
+        mut output,
+        metrics,
+        interval: report_interval,
+        thresholds,
+    } = reports;
+
     let mut sequence = RttSequence::new();
+    let mut delays = DelaySums::default();
+    let mut counters = Counters::default();
+    let mut reordering = Reordering::new();
+    let mut variation = DelayVariation::new();
+    let mut handshake = None;
+
+    let started = Instant::now();
+    let mut window = Window::new(started);
+    let mut report = report_interval.map(|period| time::interval_at((started + period).into(), period));
 
-    while let Some(timestamp) = from_statista.recv().await {
-        println!("seq: {} rtt: {:#?}", timestamp.index, timestamp.rtt);
-        sequence.add(timestamp.rtt);
+    loop {
+        let event = tokio::select! {
+            event = from_statista.recv() => match event {
+                Some(event) => event,
+                None => break,
+            },
+            _ = tick(&mut report) => {
+                let now = Instant::now();
+                let record = window.record(started, now, variation.jitter());
+                output.emit(&Record::Interval(record), SystemTime::now())?;
+                window = Window::new(now);
+                continue;
+            }
+        };
+
+        match event {
+            PingEvent::Sent => {
+                counters.sent += 1;
+                window.counters.sent += 1;
+                if let Some(metrics) = &metrics {
+                    metrics.sent();
+                }
+            }
+            PingEvent::Reply(timestamp) => {
+                counters.received += 1;
+                window.counters.received += 1;
+                window.sequence.add(timestamp.rtt);
+                if let Some(metrics) = &metrics {
+                    metrics.received(timestamp.rtt);
+                }
+
+                if let Some(d) = &timestamp.delays {
+                    delays.add(d);
+                }
+
+                let v = variation.add(timestamp.index, timestamp.rtt);
+                let extent = reordering.arrived(timestamp.index, false);
+
+                let record = ReplyRecord {
+                    seq: timestamp.index,
+                    rtt_ns: nanos(timestamp.rtt),
+                    forward_ns: timestamp.delays.as_ref().map(|d| d.forward),
+                    reverse_ns: timestamp.delays.as_ref().map(|d| d.reverse),
+                    server_ns: timestamp.delays.as_ref().map(|d| nanos(d.server)),
+                    jitter_ns: nanos(v.jitter),
+                    pdv_ns: nanos(v.pdv),
+                    ipdv_ns: v.ipdv,
+                    reordered: extent.is_some(),
+                };
+                output.emit(&Record::Reply(record), timestamp.received)?;
+
+                if let Some(extent) = extent {
+                    output.emit(&Record::Reorder { seq: timestamp.index, extent }, timestamp.received)?;
+                }
+                output.sample(Sample {
+                    index: timestamp.index,
+                    sent: Some(timestamp.sent),
+                    received: Some(timestamp.received),
+                    rtt: Some(timestamp.rtt),
+                    status: Status::Reply,
+                })?;
+
+                sequence.add(timestamp.rtt);
+            }
+            PingEvent::Lost { index, sent } => {
+                counters.lost += 1;
+                window.counters.lost += 1;
+                if let Some(metrics) = &metrics {
+                    metrics.lost();
+                }
+                output.emit(&Record::Timeout { seq: index }, SystemTime::now())?;
+                output.sample(Sample {
+                    index,
+                    sent: Some(sent),
+                    received: None,
+                    rtt: None,
+                    status: Status::Timeout,
+                })?;
+            }
+            PingEvent::Duplicate { index } => {
+                counters.duplicates += 1;
+                let now = SystemTime::now();
+                output.emit(&Record::Duplicate { seq: index }, now)?;
+                output.sample(Sample {
+                    index,
+                    sent: None,
+                    received: Some(now),
+                    rtt: None,
+                    status: Status::Duplicate,
+                })?;
+            }
+            PingEvent::Late { index } => {
+                counters.late += 1;
+                reordering.arrived(index, true);
+                let now = SystemTime::now();
+                output.emit(&Record::Late { seq: index }, now)?;
+                output.sample(Sample {
+                    index,
+                    sent: None,
+                    received: Some(now),
+                    rtt: None,
+                    status: Status::Late,
+                })?;
+            }
+            PingEvent::Error { seq, message, time } => {
+                counters.errors += 1;
+                output.emit(&Record::Error { seq, message }, time)?;
+            }
+            PingEvent::Rejected { rejection, time } => {
+                match rejection {
+                    Rejection::Unauthenticated => counters.unauthenticated += 1,
+                    Rejection::Replayed => counters.replayed += 1,
+                }
+                output.emit(&Record::Rejected { reason: rejection }, time)?;
+            }
+            PingEvent::Handshake { duration, time } => {
+                handshake = Some(duration);
+                output.emit(&Record::Handshake { handshake_ns: nanos(duration) }, time)?;
+            }
+        }
     }
-    sequence.print_stats();
+
+    // Report the last, partial interval too, unless nothing happened in it
+    let last = &window.counters;
+    if report.is_some() && last.sent + last.received + last.lost > 0 {
+        let record = window.record(started, Instant::now(), variation.jitter());
+        output.emit(&Record::Interval(record), SystemTime::now())?;
+    }
+
+    let summary = Summary {
+        sent: counters.sent,
+        received: counters.received,
+        lost: counters.lost,
+        loss_percent: counters.loss_percent(),
+        duplicates: counters.duplicates,
+        late: counters.late,
+        errors: counters.errors,
+        unauthenticated: counters.unauthenticated,
+        replayed: counters.replayed,
+        handshake_ns: handshake.map(nanos),
+        reordered: reordering.reordered,
+        reordered_percent: reordering.ratio(),
+        reorder_extent_max: reordering.max_extent,
+        reorder_extent_avg: reordering.avg_extent(),
+        rtt: sequence.summary(&percentiles),
+        delay_variation: variation.summary(&sequence, &percentiles),
+        one_way_delays: delays.summary(),
+        violations: thresholds.violations(&counters, &sequence),
+    };
+    output.emit(&Record::Summary(Box::new(summary.clone())), SystemTime::now())?;
+    output.finish()?;
+
+    Ok(summary)
+}
+
+/// Resolves on the next tick of `interval`, never if there is none.
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Statistics of a single report interval. Requests are counted as lost in
+/// the interval their wait time expired.
+struct Window {
+    start: Instant,
+    counters: Counters,
+    sequence: RttSequence,
+}
+
+impl Window {
+    fn new(start: Instant) -> Self {
+        Window {
+            start,
+            counters: Counters::default(),
+            sequence: RttSequence::new(),
+        }
+    }
+
+    /// Report of the interval ending at `end`, `jitter` is the running
+    /// estimate at that moment.
+    fn record(&self, started: Instant, end: Instant, jitter: Duration) -> IntervalRecord {
+        let has_rtt = !self.sequence.hist.is_empty();
+        let rtt = |value: fn(&RttSequence) -> Duration| has_rtt.then(|| nanos(value(&self.sequence)));
+
+        IntervalRecord {
+            start_s: self.start.duration_since(started).as_secs_f64(),
+            end_s: end.duration_since(started).as_secs_f64(),
+            sent: self.counters.sent,
+            received: self.counters.received,
+            lost: self.counters.lost,
+            loss_percent: self.counters.loss_percent(),
+            min_ns: rtt(|s| s.min),
+            avg_ns: rtt(|s| s.mean()),
+            max_ns: rtt(|s| s.max),
+            p99_ns: rtt(|s| s.percentile(99.)),
+            jitter_ns: nanos(jitter),
+        }
+    }
+}
+
+/// Ping-style packet counters. Late replies don't make requests less lost:
+/// RTT of such requests exceeded wait time.
+#[derive(Default)]
+struct Counters {
+    sent: u64,
+    received: u64,
+    lost: u64,
+    duplicates: u64,
+    late: u64,
+    errors: u64,
+    unauthenticated: u64,
+    replayed: u64,
+}
+
+impl Counters {
+    fn loss_percent(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.;
+        }
+        self.lost as f64 * 100. / self.sent as f64
+    }
+}
+
+/// Delay variation of a single reply.
+struct Variation {
+    jitter: Duration,
+    pdv: Duration,
+    ipdv: Option<i64>,
+}
+
+/// Delay variation metrics computed from RTT of replies in arrival order:
+///
+/// * interarrival jitter of RFC 3550, smoothed with 1/16 gain,
+/// * min-based packet delay variation (PDV) of RFC 5481, i.e. RTT above the
+///   minimal one. Live values use the minimum seen so far, the summary uses
+///   the minimum of the whole run,
+/// * inter-packet delay variation (IPDV) of RFC 5481, i.e. RTT difference of
+///   replies to consecutive requests. It is undefined if the previous request
+///   got no reply.
+struct DelayVariation {
+    last: Option<(u64, Duration)>,
+    jitter: f64,
+    min_rtt: Duration,
+    ipdv_count: u64,
+    ipdv_min: i64,
+    ipdv_max: i64,
+    ipdv_abs_sum: u128,
+}
+
+impl DelayVariation {
+    fn new() -> Self {
+        DelayVariation {
+            last: None,
+            jitter: 0.,
+            min_rtt: Duration::MAX,
+            ipdv_count: 0,
+            ipdv_min: i64::MAX,
+            ipdv_max: i64::MIN,
+            ipdv_abs_sum: 0,
+        }
+    }
+
+    fn add(&mut self, index: u64, rtt: Duration) -> Variation {
+        let rtt_nanos = rtt.as_nanos() as i64;
+        let mut ipdv = None;
+
+        if let Some((last_index, last_rtt)) = self.last {
+            let diff = rtt_nanos - last_rtt.as_nanos() as i64;
+            self.jitter += (diff.unsigned_abs() as f64 - self.jitter) / 16.;
+
+            if index == last_index + 1 {
+                self.ipdv_count += 1;
+                self.ipdv_min = self.ipdv_min.min(diff);
+                self.ipdv_max = self.ipdv_max.max(diff);
+                self.ipdv_abs_sum += diff.unsigned_abs() as u128;
+                ipdv = Some(diff);
+            }
+        }
+
+        self.last = Some((index, rtt));
+        self.min_rtt = self.min_rtt.min(rtt);
+
+        Variation {
+            jitter: self.jitter(),
+            pdv: rtt - self.min_rtt,
+            ipdv,
+        }
+    }
+
+    fn jitter(&self) -> Duration {
+        Duration::from_nanos(self.jitter.round() as u64)
+    }
+
+    /// PDV percentiles are RTT percentiles shifted by the minimal RTT, so
+    /// they are taken from `sequence` which has all RTT of the run.
+    fn summary(&self, sequence: &RttSequence, percentiles: &[f64]) -> Option<VariationSummary> {
+        self.last?;
+
+        let ipdv = |value| (self.ipdv_count > 0).then_some(value);
+
+        Some(VariationSummary {
+            jitter_ns: nanos(self.jitter()),
+            pdv: percentiles
+                .iter()
+                .map(|p| Percentile {
+                    p: *p,
+                    value_ns: nanos(sequence.percentile(*p).saturating_sub(sequence.min)),
+                })
+                .collect(),
+            ipdv_min_ns: ipdv(self.ipdv_min),
+            ipdv_max_ns: ipdv(self.ipdv_max),
+            ipdv_avg_abs_ns: (self.ipdv_count > 0).then(|| (self.ipdv_abs_sum / self.ipdv_count as u128) as u64),
+        })
+    }
+}
+
+/// How many recent arrivals are kept to compute reordering extent, larger
+/// extents are capped at this value.
+const REORDER_HISTORY: usize = 1024;
+
+/// Reordering metrics of RFC 4737: a reply is reordered if its index is lower
+/// than the next expected one, i.e. a reply to a newer request has already
+/// arrived. Reordering extent of such reply is the distance in arrival order
+/// to the earliest reply to a newer request.
+struct Reordering {
+    next_expected: u64,
+    arrivals: u64,
+    reordered: u64,
+    /// Indices of recent arrivals, the last one arrived at `arrivals - 1`.
+    history: VecDeque<u64>,
+    max_extent: u64,
+    extent_sum: u64,
 }
 
-struct RttSequence(Vec<Duration>);
+impl Reordering {
+    fn new() -> Self {
+        Reordering {
+            next_expected: 0,
+            arrivals: 0,
+            reordered: 0,
+            history: VecDeque::with_capacity(REORDER_HISTORY),
+            max_extent: 0,
+            extent_sum: 0,
+        }
+    }
+
+    /// Registers arrival of reply to request `index`, returns its
+    /// reordering extent if it is reordered. `late` replies come after their
+    /// request was counted as lost and don't move the next expected index.
+    fn arrived(&mut self, index: u64, late: bool) -> Option<u64> {
+        let mut reordered = None;
+
+        if index < self.next_expected {
+            self.reordered += 1;
+
+            let earliest_newer = self.history.iter().position(|i| *i > index).unwrap_or(0);
+            let extent = (self.history.len() - earliest_newer) as u64;
+            self.max_extent = self.max_extent.max(extent);
+            self.extent_sum += extent;
+            reordered = Some(extent);
+        } else if !late {
+            self.next_expected = index + 1;
+        }
+
+        self.arrivals += 1;
+        if self.history.len() == REORDER_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(index);
+
+        reordered
+    }
+
+    fn ratio(&self) -> f64 {
+        if self.arrivals == 0 {
+            return 0.;
+        }
+        self.reordered as f64 * 100. / self.arrivals as f64
+    }
+
+    fn avg_extent(&self) -> f64 {
+        if self.reordered == 0 {
+            return 0.;
+        }
+        self.extent_sum as f64 / self.reordered as f64
+    }
+}
+
+#[derive(Default)]
+struct DelaySums {
+    count: u64,
+    forward: i128,
+    reverse: i128,
+    server: Duration,
+}
+
+impl DelaySums {
+    fn add(&mut self, delays: &OneWayDelays) {
+        self.count += 1;
+        self.forward += delays.forward as i128;
+        self.reverse += delays.reverse as i128;
+        self.server += delays.server;
+    }
+
+    fn summary(&self) -> Option<OneWaySummary> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let n = self.count as i128;
+
+        Some(OneWaySummary {
+            avg_forward_ns: (self.forward / n) as i64,
+            avg_reverse_ns: (self.reverse / n) as i64,
+            avg_server_ns: (self.server.as_nanos() / self.count as u128) as u64,
+        })
+    }
+}
+
+/// RTT statistics in constant memory: min, max, mean and variance are
+/// computed exactly in a streaming fashion (Welford's algorithm), while
+/// percentiles come from a histogram and are accurate within
+/// `histogram::RELATIVE_ERROR`.
+struct RttSequence {
+    hist: Histogram,
+    min: Duration,
+    max: Duration,
+    mean: f64,
+    m2: f64,
+}
 
 impl RttSequence {
     fn new() -> Self {
-        RttSequence(Vec::with_capacity(1024))
+        RttSequence {
+            hist: Histogram::new(),
+            min: Duration::MAX,
+            max: Duration::ZERO,
+            mean: 0.,
+            m2: 0.,
+        }
     }
 
     fn add(&mut self, rtt: Duration) {
-        self.0.push(rtt)
+        self.hist.record(rtt);
+        self.min = self.min.min(rtt);
+        self.max = self.max.max(rtt);
+
+        let value = rtt.as_nanos() as f64;
+        let delta = value - self.mean;
+        self.mean += delta / self.hist.len() as f64;
+        self.m2 += delta * (value - self.mean);
     }
 
     fn mean(&self) -> Duration {
-        let avg = self.0.iter().sum::<Duration>().as_nanos() / self.0.len() as u128;
-        Duration::from_nanos(u64::try_from(avg).unwrap())
+        Duration::from_nanos(self.mean.round() as u64)
     }
 
     fn std_deviation(&self) -> Duration {
-        let avg = self.mean();
-
-        let variance = self.0
-            .iter()
-            .map(|value| {
-                let diff = avg.as_nanos().abs_diff((*value).as_nanos());
-                diff * diff
-            })
-            .sum::<u128>() as f64 / self.0.len() as f64;
+        let variance = self.m2 / self.hist.len() as f64;
 
         Duration::from_secs_f64(variance.sqrt() / 1_000_000_000.)
     }
 
-    fn print_stats(&mut self) {
-        if self.0.is_empty() {
-            println!("no statistics collected");
-            return;
+    /// Percentile `p` (0 to 100), linearly interpolated between the closest
+    /// ranks. For `p = 50` and even number of samples it is the mean of the
+    /// two middle ones. Exact extremes are used for the first and last rank.
+    fn percentile(&self, p: f64) -> Duration {
+        let last = self.hist.len() - 1;
+        let rank = p / 100. * last as f64;
+
+        let value_at = |rank: u64| match rank {
+            0 => self.min,
+            r if r == last => self.max,
+            r => self.hist.value_at_rank(r).clamp(self.min, self.max),
+        };
+        let lo = value_at(rank.floor() as u64).as_nanos() as f64;
+        let hi = value_at(rank.ceil() as u64).as_nanos() as f64;
+
+        Duration::from_nanos((lo + (hi - lo) * rank.fract()).round() as u64)
+    }
+
+    fn summary(&self, percentiles: &[f64]) -> Option<RttSummary> {
+        if self.hist.is_empty() {
+            return None;
+        }
+
+        Some(RttSummary {
+            min_ns: nanos(self.min),
+            median_ns: nanos(self.percentile(50.)),
+            avg_ns: nanos(self.mean()),
+            std_dev_ns: nanos(self.std_deviation()),
+            max_ns: nanos(self.max),
+            percentiles: percentiles
+                .iter()
+                .map(|p| Percentile {
+                    p: *p,
+                    value_ns: nanos(self.percentile(*p)),
+                })
+                .collect(),
+            histogram: self.histogram(),
+        })
+    }
+
+    /// Histogram with logarithmic 1-2-5 buckets, from the first non-empty
+    /// bucket up to the last one.
+    fn histogram(&self) -> Vec<HistogramBucket> {
+        let bounds = (0..).flat_map(|exp| [1, 2, 5].map(|m| m * 10u64.pow(exp)));
+
+        let mut buckets = Vec::new();
+        let mut counted = 0;
+
+        for bound in bounds {
+            if counted == self.hist.len() {
+                break;
+            }
+
+            let n = self.hist.count_below(Duration::from_nanos(bound)) - counted;
+            if n > 0 || !buckets.is_empty() {
+                buckets.push(HistogramBucket {
+                    below_ns: bound,
+                    count: n,
+                });
+            }
+            counted += n;
         }
 
-        self.0.sort();
+        buckets
+    }
+}
 
-        let min = self.0.iter().min().unwrap();
-        let max = self.0.iter().max().unwrap();
-        let avg = self.mean();
-        let std_dev = self.std_deviation();
-        let median = self.0.get(self.0.len() / 2).unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        println!("\nRTT statistics:");
-        println!("min = {min:?}");
-        println!("med = {median:?}");
-        println!("avg = {avg:?}");
-        println!("std_dev = {std_dev:?}");
-        println!("max = {max:?}");
+    #[test]
+    fn late_reply_keeps_next_expected() {
+        let mut reordering = Reordering::new();
+        assert_eq!(reordering.arrived(0, false), None);
+        assert_eq!(reordering.arrived(5, true), None);
+        assert_eq!(reordering.arrived(1, false), None);
+        assert_eq!(reordering.arrived(3, false), None);
+        assert_eq!(reordering.arrived(2, false), Some(3));
+        assert_eq!((reordering.arrivals, reordering.reordered), (5, 1));
     }
 }